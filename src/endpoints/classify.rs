@@ -1,26 +1,59 @@
-use crate::{endpoints::EndpointState, errors::ClassifyError, utils::RequestClientIp};
+use crate::{
+    endpoints::EndpointState,
+    errors::ClassifyError,
+    geoip::{Coordinates, Location},
+    telemetry,
+    utils::RequestClientIp,
+};
 use actix_web::{http, web::Data, HttpRequest, HttpResponse};
 use chrono::{DateTime, Utc};
-use maxminddb::{self, geoip2};
 use serde::Serializer;
 use serde_derive::Serialize;
 
 #[derive(Serialize)]
-struct ClientClassification<'a> {
+struct ClientClassification {
     request_time: DateTime<Utc>,
 
     #[serde(serialize_with = "country_iso_code")]
-    country: Option<geoip2::Country<'a>>,
+    country: Option<Location>,
+
+    #[serde(flatten)]
+    region: Option<RegionClassification>,
+}
+
+/// Finer grained details of a client's location. These are only known when
+/// using a City database, and are null otherwise.
+#[derive(Debug, Default, PartialEq, Serialize)]
+struct RegionClassification {
+    continent: Option<String>,
+    subdivision: Option<String>,
+    city: Option<String>,
+    time_zone: Option<String>,
+    location: Option<Coordinates>,
+}
+
+impl From<Option<&Location>> for RegionClassification {
+    fn from(location: Option<&Location>) -> Self {
+        match location {
+            Some(location) => Self {
+                continent: location.continent_code.clone(),
+                subdivision: location.subdivision_code.clone(),
+                city: location.city_name.clone(),
+                time_zone: location.time_zone.clone(),
+                location: location.coordinates,
+            },
+            None => Self::default(),
+        }
+    }
 }
 
 fn country_iso_code<S: Serializer>(
-    country_info: &Option<geoip2::Country>,
+    location: &Option<Location>,
     serializer: S,
 ) -> Result<S::Ok, S::Error> {
-    let iso_code: Option<&str> = country_info
-        .clone()
-        .and_then(|country_info| country_info.country)
-        .and_then(|country| country.iso_code);
+    let iso_code: Option<&str> = location
+        .as_ref()
+        .and_then(|location| location.country_code.as_deref());
 
     match iso_code {
         Some(code) => serializer.serialize_str(code),
@@ -28,22 +61,40 @@ fn country_iso_code<S: Serializer>(
     }
 }
 
-impl<'a> Default for ClientClassification<'a> {
+impl Default for ClientClassification {
     fn default() -> Self {
         Self {
             request_time: Utc::now(),
             country: None,
+            region: None,
         }
     }
 }
 
+/// Classify the client by country, and also by region if the
+/// `EXTENDED_CLASSIFICATION` setting is enabled.
 pub async fn classify_client(
     req: HttpRequest,
     state: Data<EndpointState>,
 ) -> Result<HttpResponse, ClassifyError> {
-    state
-        .geoip
-        .locate(req.client_ip()?)
+    let extended = state.extended_classification;
+    classify(req, state, extended)
+}
+
+/// Classify the client by country and region, regardless of settings.
+pub async fn classify_client_v2(
+    req: HttpRequest,
+    state: Data<EndpointState>,
+) -> Result<HttpResponse, ClassifyError> {
+    classify(req, state, true)
+}
+
+fn classify(
+    req: HttpRequest,
+    state: Data<EndpointState>,
+    extended: bool,
+) -> Result<HttpResponse, ClassifyError> {
+    telemetry::locate(&req, &state.geoip, req.client_ip()?)
         .map(move |country| {
             let mut response = HttpResponse::Ok();
             response.append_header((
@@ -51,6 +102,7 @@ pub async fn classify_client(
                 "max-age=0, no-cache, no-store, must-revalidate",
             ));
             response.json(ClientClassification {
+                region: extended.then(|| RegionClassification::from(country.as_ref())),
                 country,
                 ..Default::default()
             })
@@ -60,7 +112,10 @@ pub async fn classify_client(
 
 #[cfg(test)]
 mod tests {
-    use crate::{endpoints::EndpointState, geoip::GeoIp};
+    use crate::{
+        endpoints::EndpointState,
+        geoip::{Coordinates, GeoIp, Location},
+    };
     use actix_web::{
         http,
         test::{self, TestRequest},
@@ -68,7 +123,6 @@ mod tests {
         App,
     };
     use chrono::DateTime;
-    use maxminddb::geoip2;
     use serde_json::{json, Value};
     use std::{collections::HashSet, sync::Arc};
 
@@ -79,17 +133,9 @@ mod tests {
         let value = serde_json::to_value(&classification).unwrap();
         assert_eq!(*value.get("country").unwrap(), Value::Null);
 
-        classification.country = Some(geoip2::Country {
-            country: Some(geoip2::country::Country {
-                geoname_id: None,
-                iso_code: Some("US"),
-                names: None,
-                is_in_european_union: None,
-            }),
-            continent: None,
-            registered_country: None,
-            represented_country: None,
-            traits: None,
+        classification.country = Some(Location {
+            country_code: Some("US".to_owned()),
+            ..Location::default()
         });
 
         let value = serde_json::to_value(&classification).unwrap();
@@ -99,6 +145,91 @@ mod tests {
         );
     }
 
+    #[actix_rt::test]
+    async fn test_extended_classification_serialization() {
+        let mut classification = super::ClientClassification {
+            region: Some(super::RegionClassification::from(None)),
+            ..Default::default()
+        };
+
+        let value = serde_json::to_value(&classification).unwrap();
+        for field in ["continent", "subdivision", "city", "time_zone", "location"] {
+            assert_eq!(*value.get(field).unwrap(), Value::Null);
+        }
+
+        let location = Location {
+            country_code: Some("US".to_owned()),
+            continent_code: Some("NA".to_owned()),
+            subdivision_code: Some("CA".to_owned()),
+            city_name: Some("Mountain View".to_owned()),
+            time_zone: Some("America/Los_Angeles".to_owned()),
+            coordinates: Some(Coordinates {
+                latitude: 37.4,
+                longitude: -122.1,
+                accuracy_radius: Some(10),
+            }),
+            ..Location::default()
+        };
+        classification.region = Some(super::RegionClassification::from(Some(&location)));
+        classification.country = Some(location);
+
+        let value = serde_json::to_value(&classification).unwrap();
+        assert_eq!(*value.get("country").unwrap(), json!("US"));
+        assert_eq!(*value.get("continent").unwrap(), json!("NA"));
+        assert_eq!(*value.get("subdivision").unwrap(), json!("CA"));
+        assert_eq!(*value.get("city").unwrap(), json!("Mountain View"));
+        assert_eq!(
+            *value.get("time_zone").unwrap(),
+            json!("America/Los_Angeles")
+        );
+        assert_eq!(
+            *value.get("location").unwrap(),
+            json!({"latitude": 37.4, "longitude": -122.1, "accuracy_radius": 10})
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_classify_endpoint_extended_fields() -> Result<(), Box<dyn std::error::Error>> {
+        for extended_classification in [false, true] {
+            let state = EndpointState {
+                geoip: Arc::new(GeoIp::builder().path("./GeoLite2-Country.mmdb").build()?),
+                extended_classification,
+                ..EndpointState::default()
+            };
+            let service = test::init_service(
+                App::new()
+                    .app_data(Data::new(state))
+                    .route("/", web::get().to(super::classify_client))
+                    .route("/v2", web::get().to(super::classify_client_v2)),
+            )
+            .await;
+
+            let request = TestRequest::get()
+                .insert_header(("x-forwarded-for", "7.7.7.7"))
+                .to_request();
+            let value: serde_json::Value = test::call_and_read_body_json(&service, request).await;
+            assert_eq!(
+                value.get("continent").is_some(),
+                extended_classification,
+                "The setting should control extended fields on the original endpoint"
+            );
+
+            let request = TestRequest::get()
+                .uri("/v2")
+                .insert_header(("x-forwarded-for", "7.7.7.7"))
+                .to_request();
+            let value: serde_json::Value = test::call_and_read_body_json(&service, request).await;
+            assert_eq!(*value.get("country").unwrap(), json!("US"));
+            assert_eq!(
+                *value.get("continent").unwrap(),
+                json!("NA"),
+                "The v2 endpoint should always include extended fields"
+            );
+        }
+
+        Ok(())
+    }
+
     #[actix_rt::test]
     async fn test_classify_endpoint() -> Result<(), Box<dyn std::error::Error>> {
         let state = EndpointState {
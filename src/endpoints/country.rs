@@ -1,9 +1,19 @@
-use crate::{endpoints::EndpointState, errors::ClassifyError, utils::RequestClientIp};
-use actix_web::{http, web::Data, web::Query, HttpRequest, HttpResponse};
-use cadence::prelude::*;
-use once_cell::sync::Lazy;
-use regex::Regex;
+use crate::{
+    endpoints::EndpointState,
+    errors::ClassifyError,
+    keys::{ApiKey, Capability, Endpoint, KeyRejection},
+    ratelimit, telemetry,
+    utils::RequestClientIp,
+};
+use actix_web::{
+    http::{self, StatusCode},
+    web::{BytesMut, Data, Payload, Query},
+    HttpRequest, HttpResponse,
+};
+use chrono::Utc;
+use futures::StreamExt;
 use serde_derive::{Deserialize, Serialize};
+use std::{net::IpAddr, time::Instant};
 
 #[derive(Serialize)]
 struct CountryResponse<'a> {
@@ -11,38 +21,214 @@ struct CountryResponse<'a> {
     country_name: &'a str,
 }
 
+/// The error format used by Mozilla Location Services.
 #[derive(Serialize)]
-struct CountryNotFoundResponse<'a> {
-    errors: &'a [CountryNotFoundError<'a>],
-    code: i16,
+struct ErrorResponse<'a> {
+    errors: &'a [ErrorDetail<'a>],
+    code: u16,
     message: &'a str,
 }
 
 #[derive(Serialize)]
-struct CountryNotFoundError<'a> {
+struct ErrorDetail<'a> {
     domain: &'a str,
     reason: &'a str,
     message: &'a str,
 }
 
-static COUNTRY_NOT_FOUND_RESPONSE: CountryNotFoundResponse = CountryNotFoundResponse {
+static COUNTRY_NOT_FOUND_RESPONSE: ErrorResponse = ErrorResponse {
     code: 404,
     message: "Not found",
-    errors: &[CountryNotFoundError {
+    errors: &[ErrorDetail {
         domain: "geolocation",
         reason: "notFound",
         message: "Not found",
     }],
 };
 
-static DOWNSTREAM_KEY: Lazy<regex::Regex> =
-    Lazy::new(|| Regex::new(r"^firefox-downstream-\w{1,40}$").unwrap());
+/// Build a response in the same format as `COUNTRY_NOT_FOUND_RESPONSE`.
+fn error_response(status: StatusCode, reason: &str, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(ErrorResponse {
+        code: status.as_u16(),
+        message,
+        errors: &[ErrorDetail {
+            domain: "geolocation",
+            reason,
+            message,
+        }],
+    })
+}
 
 #[derive(Deserialize, Debug)]
 pub struct Params {
     key: String,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct LookupParams {
+    /// An IP to look up instead of the client's, for keys with the `lookup` capability.
+    ip: Option<String>,
+}
+
+/// Find the API key for a request. It may be given as a bearer token in the
+/// `Authorization` header, in the `X-Api-Key` header, or in the `key` query
+/// parameter, in that order of precedence. Headers keep keys out of access logs.
+fn request_api_key(req: &HttpRequest) -> Option<String> {
+    let headers = req.headers();
+    let bearer = headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim().to_owned());
+    let header = || {
+        headers
+            .get("X-Api-Key")
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().to_owned())
+    };
+    let query = || {
+        Query::<Params>::from_query(req.query_string())
+            .ok()
+            .map(|params| params.into_inner().key)
+    };
+    bearer.or_else(header).or_else(query)
+}
+
+/// An API key that was accepted for a request.
+struct AcceptedKey {
+    /// The key as it was given.
+    key: String,
+    /// How the key is identified in metrics: the key itself for keys in the
+    /// API keys file, or the name of the pattern for downstream keys.
+    tag: String,
+    /// The details of the key, if it is listed in the API keys file.
+    api_key: Option<ApiKey>,
+}
+
+/// Check the API key provided with a request to `endpoint`, and count the
+/// request in a metric named after the endpoint, tagged with the key. Returns
+/// the key if it is listed in the API keys file or matches a downstream key
+/// pattern, or the response to send instead if the key is not accepted.
+fn check_api_key(
+    req: &HttpRequest,
+    state: &EndpointState,
+    endpoint: Endpoint,
+) -> Result<AcceptedKey, HttpResponse> {
+    let metric_name = endpoint.name();
+    let metrics = &state.metrics;
+
+    match request_api_key(req) {
+        Some(key) => {
+            // check the list of known API keys first, so that listed keys
+            // keep their own settings even if they match a pattern
+            let (tag, api_key) = match state.api_keys.get(&key) {
+                Some(api_key) => match api_key.check(endpoint, Utc::now().date_naive()) {
+                    Ok(()) => (key.clone(), Some(api_key)),
+                    Err(rejection) => {
+                        let (tag, response) = match rejection {
+                            KeyRejection::Disabled => (
+                                "disabled-key",
+                                HttpResponse::Unauthorized().body("Disabled key"),
+                            ),
+                            KeyRejection::Expired => (
+                                "expired-key",
+                                HttpResponse::Unauthorized().body("Expired key"),
+                            ),
+                            KeyRejection::EndpointNotAllowed => (
+                                "forbidden-key",
+                                error_response(
+                                    StatusCode::FORBIDDEN,
+                                    "forbidden",
+                                    "This key may not be used with this endpoint",
+                                ),
+                            ),
+                        };
+                        metrics
+                            .incr_with_tags(metric_name)
+                            .with_tag("api_key", tag)
+                            .send();
+                        return Err(response);
+                    }
+                },
+                // if that misses, check for downstream key patterns, see
+                // readme for details
+                None => match state.downstream_keys.matching(&key) {
+                    Some(name) => {
+                        // Anyone can make up a downstream key, so they only
+                        // work with the basic endpoint.
+                        if endpoint != Endpoint::Country {
+                            metrics
+                                .incr_with_tags(metric_name)
+                                .with_tag("api_key", "forbidden-key")
+                                .send();
+                            return Err(error_response(
+                                StatusCode::FORBIDDEN,
+                                "forbidden",
+                                "This key may not be used with this endpoint",
+                            ));
+                        }
+                        (name.to_owned(), None)
+                    }
+                    None => {
+                        metrics
+                            .incr_with_tags(metric_name)
+                            .with_tag("api_key", "invalid-key")
+                            .send();
+                        return Err(HttpResponse::Unauthorized().body("Wrong key"));
+                    }
+                },
+            };
+
+            metrics
+                .incr_with_tags(metric_name)
+                .with_tag("api_key", state.api_key_tags.get(&tag))
+                .send();
+            Ok(AcceptedKey { key, tag, api_key })
+        }
+        None => Err(HttpResponse::Unauthorized().body("Wrong key")),
+    }
+}
+
+/// Take a token from the rate limit bucket for a key used with a request to
+/// `/v1/country` or `/v1/country/batch`. Keys from the API keys file use their own limit, and each
+/// downstream key uses a configured default. Returns the response to send
+/// instead if the key has made too many requests.
+fn check_rate_limit(state: &EndpointState, accepted: &AcceptedKey) -> Result<(), HttpResponse> {
+    let (limiter, limit) = match &accepted.api_key {
+        Some(api_key) => (&state.api_key_limiter, api_key.rate_limit),
+        None => (
+            &state.downstream_key_limiter,
+            state.downstream_key_rate_limit,
+        ),
+    };
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+
+    match limiter.check(accepted.key.clone(), &limit, Instant::now()) {
+        Ok(()) => Ok(()),
+        Err(retry_after) => {
+            state
+                .metrics
+                .incr_with_tags("country_throttled")
+                .with_tag("api_key", state.api_key_tags.get(&accepted.tag))
+                .send();
+            let mut response = error_response(
+                StatusCode::TOO_MANY_REQUESTS,
+                "rateLimitExceeded",
+                "Too many requests",
+            );
+            response.headers_mut().insert(
+                http::header::RETRY_AFTER,
+                ratelimit::retry_after(retry_after),
+            );
+            Err(response)
+        }
+    }
+}
+
 pub async fn get_country(
     req: HttpRequest,
     state: Data<EndpointState>,
@@ -50,46 +236,56 @@ pub async fn get_country(
     let metrics = &state.metrics;
 
     // check provided API Key
-    match Query::<Params>::from_query(req.query_string()) {
-        Ok(req_query) => {
-            // check for downstream firefox regex pattern, see readme for details
-            if !DOWNSTREAM_KEY.is_match(&req_query.key) {
-                // if that misses, check list of known API keys
-                if !state.api_keys_hashset.contains(&req_query.key) {
-                    metrics
-                        .incr_with_tags("country")
-                        .with_tag("api_key", "invalid-key")
-                        .send();
-                    return Ok(HttpResponse::Unauthorized().body("Wrong key"));
+    let accepted = match check_api_key(&req, &state, Endpoint::Country) {
+        Ok(accepted) => accepted,
+        Err(response) => return Ok(response),
+    };
+    if let Err(response) = check_rate_limit(&state, &accepted) {
+        return Ok(response);
+    }
+
+    // Privileged keys may ask about any IP, instead of the client's own.
+    let requested_ip = Query::<LookupParams>::from_query(req.query_string())
+        .ok()
+        .and_then(|params| params.into_inner().ip);
+    let ip = match requested_ip {
+        Some(requested_ip) => {
+            let can_lookup = accepted
+                .api_key
+                .is_some_and(|key| key.has_capability(Capability::Lookup));
+            if !can_lookup {
+                return Ok(error_response(
+                    StatusCode::FORBIDDEN,
+                    "forbidden",
+                    "This key may not look up arbitrary IPs",
+                ));
+            }
+            match requested_ip.trim().parse::<IpAddr>() {
+                Ok(ip) => ip,
+                Err(_) => {
+                    return Ok(error_response(
+                        StatusCode::BAD_REQUEST,
+                        "parseError",
+                        "Invalid IP address",
+                    ))
                 }
             }
-
-            metrics
-                .incr_with_tags("country")
-                .with_tag("api_key", &req_query.key)
-                .send();
         }
-        _ => {
-            return Ok(HttpResponse::Unauthorized().body("Wrong key"));
-        }
-    }
+        None => req.client_ip()?,
+    };
 
     // return country if we can identify it based on IP address
-    return state
-        .geoip
-        .locate(req.client_ip()?)
+    telemetry::locate(&req, &state.geoip, ip)
         .map(move |location| {
-            let country_opt = match location {
-                Some(x) => x.country,
-                None => None,
+            let location = match location {
+                Some(location) if location.country_code.is_some() => location,
+                _ => {
+                    let mut response = HttpResponse::NotFound();
+                    metrics.incr_with_tags("country_miss").send();
+                    return response.json(&COUNTRY_NOT_FOUND_RESPONSE);
+                }
             };
 
-            if country_opt.is_none() {
-                let mut response = HttpResponse::NotFound();
-                metrics.incr_with_tags("country_miss").send();
-                return response.json(&COUNTRY_NOT_FOUND_RESPONSE);
-            }
-
             let mut response = HttpResponse::Ok();
             response.append_header((
                 http::header::CACHE_CONTROL,
@@ -98,30 +294,141 @@ pub async fn get_country(
 
             metrics.incr_with_tags("country_hit").send();
 
-            let country = country_opt.unwrap();
             response.json(CountryResponse {
-                country_code: country.iso_code.unwrap_or_default(),
-                country_name: match country.names {
-                    Some(x) => x["en"],
-                    None => "",
+                country_code: location.country_code.as_deref().unwrap_or_default(),
+                country_name: location.country_name.as_deref().unwrap_or_default(),
+            })
+        })
+        .map_err(|err| ClassifyError::from_source("Future failure", err))
+}
+
+#[derive(Serialize)]
+struct BatchResponse {
+    results: Vec<BatchResult>,
+}
+
+/// The location of one of the IPs in a batch, or why it couldn't be found.
+#[derive(Debug, PartialEq, Serialize)]
+struct BatchResult {
+    ip: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    country_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    country_name: Option<String>,
+    /// "notFound" if the IP could not be located, or "invalidIp" if it could
+    /// not be parsed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+}
+
+/// The most bytes allowed for each IP in a batch request body. The longest
+/// IPv6 address is 45 characters, leaving room for quotes, a comma and
+/// whitespace.
+const BATCH_BYTES_PER_IP: usize = 64;
+
+fn batch_too_large(max_size: usize) -> HttpResponse {
+    error_response(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "batchTooLarge",
+        &format!("At most {} IP addresses may be looked up at once", max_size),
+    )
+}
+
+/// Look up the countries of a JSON array of IPs posted in the request body.
+pub async fn get_country_batch(
+    req: HttpRequest,
+    state: Data<EndpointState>,
+    mut payload: Payload,
+) -> Result<HttpResponse, ClassifyError> {
+    let accepted = match check_api_key(&req, &state, Endpoint::CountryBatch) {
+        Ok(accepted) => accepted,
+        Err(response) => return Ok(response),
+    };
+    if let Err(response) = check_rate_limit(&state, &accepted) {
+        return Ok(response);
+    }
+
+    // Stop reading as soon as the body is too long to be a batch of the
+    // allowed size, rather than buffering and parsing all of it.
+    let max_body_size = (state.country_batch_max_size + 1) * BATCH_BYTES_PER_IP;
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|err| ClassifyError::from_source("Request body", err))?;
+        if body.len() + chunk.len() > max_body_size {
+            return Ok(batch_too_large(state.country_batch_max_size));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    let ips: Vec<String> = match serde_json::from_slice(&body) {
+        Ok(ips) => ips,
+        Err(_) => {
+            return Ok(error_response(
+                StatusCode::BAD_REQUEST,
+                "parseError",
+                "Expected a JSON array of IP addresses",
+            ))
+        }
+    };
+    if ips.len() > state.country_batch_max_size {
+        return Ok(batch_too_large(state.country_batch_max_size));
+    }
+
+    let results = ips
+        .into_iter()
+        .map(|ip| {
+            let address = match ip.trim().parse::<IpAddr>() {
+                Ok(address) => address,
+                Err(_) => {
+                    return Ok(BatchResult {
+                        ip,
+                        country_code: None,
+                        country_name: None,
+                        error: Some("invalidIp"),
+                    })
+                }
+            };
+            Ok(match telemetry::locate(&req, &state.geoip, address)? {
+                Some(location) if location.country_code.is_some() => BatchResult {
+                    ip,
+                    country_code: location.country_code,
+                    country_name: Some(location.country_name.unwrap_or_default()),
+                    error: None,
+                },
+                _ => BatchResult {
+                    ip,
+                    country_code: None,
+                    country_name: None,
+                    error: Some("notFound"),
                 },
             })
         })
-        .map_err(|err| ClassifyError::from_source("Future failure", err));
+        .collect::<Result<_, ClassifyError>>()?;
+
+    Ok(HttpResponse::Ok()
+        .append_header((
+            http::header::CACHE_CONTROL,
+            "max-age=0, no-cache, no-store, must-revalidate",
+        ))
+        .json(BatchResponse { results }))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{endpoints::EndpointState, geoip::GeoIp, metrics::tests::TestMetricSink};
+    use crate::{
+        endpoints::EndpointState,
+        geoip::GeoIp,
+        keys::{ApiKey, ApiKeys, Capability, Endpoint},
+        metrics::{tests::TestMetricSink, Metrics},
+        ratelimit::{RateLimit, RateLimiter},
+    };
     use actix_web::{
         test::{self, TestRequest},
         web::{self, Data},
         App,
     };
-    use cadence::StatsdClient;
     use serde_json::{self, json};
     use std::{
-        collections::HashSet,
         ops::Deref,
         sync::{Arc, Mutex},
     };
@@ -129,15 +436,14 @@ mod tests {
     #[actix_rt::test]
     async fn test_country_endpoint() -> Result<(), Box<dyn std::error::Error>> {
         let log = Arc::new(Mutex::new(Vec::new()));
-        let metrics = Arc::new(StatsdClient::from_sink(
+        let metrics = Arc::new(Metrics::from_sink(
             "test",
             TestMetricSink { log: log.clone() },
         ));
-        let mut api_keys_hashset = HashSet::new();
-        api_keys_hashset.insert("testkey".to_string());
+        let api_keys = Arc::new(ApiKeys::new(vec![ApiKey::new("testkey")]));
 
         let state = EndpointState {
-            api_keys_hashset,
+            api_keys,
             geoip: Arc::new(
                 GeoIp::builder()
                     .path("./GeoLite2-Country.mmdb")
@@ -244,7 +550,7 @@ mod tests {
                 "test.country_miss:1|c",
                 "test.country:1|c|#api_key:testkey",
                 "test.country_hit:1|c",
-                "test.country:1|c|#api_key:firefox-downstream-foo_bar",
+                "test.country:1|c|#api_key:firefox-downstream",
                 "test.country_hit:1|c",
                 "test.country:1|c|#api_key:invalid-key",
             ]
@@ -252,4 +558,434 @@ mod tests {
 
         Ok(())
     }
+
+    #[actix_rt::test]
+    async fn test_country_batch_endpoint() -> Result<(), Box<dyn std::error::Error>> {
+        let api_keys = Arc::new(ApiKeys::new(vec![ApiKey::new("testkey")]));
+
+        let state = EndpointState {
+            api_keys,
+            country_batch_max_size: 3,
+            geoip: Arc::new(GeoIp::builder().path("./GeoLite2-Country.mmdb").build()?),
+            ..EndpointState::default()
+        };
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .route("/", web::post().to(super::get_country_batch)),
+        )
+        .await;
+
+        let unauthorized = TestRequest::post()
+            .uri("/?key=wrongkey")
+            .set_json(json!(["7.7.7.7"]))
+            .to_request();
+        let response = test::call_service(&service, unauthorized).await;
+        assert_eq!(response.status(), 401);
+
+        let request = TestRequest::post()
+            .uri("/?key=testkey")
+            .set_json(json!(["7.7.7.7", "127.0.0.1", "not an ip"]))
+            .to_request();
+        let value: serde_json::Value = test::call_and_read_body_json(&service, request).await;
+        assert_eq!(
+            value,
+            json!({"results": [
+                {"ip": "7.7.7.7", "country_code": "US", "country_name": "United States"},
+                {"ip": "127.0.0.1", "error": "notFound"},
+                {"ip": "not an ip", "error": "invalidIp"},
+            ]})
+        );
+
+        let too_large = TestRequest::post()
+            .uri("/?key=testkey")
+            .set_json(json!(["1.1.1.1", "2.2.2.2", "3.3.3.3", "4.4.4.4"]))
+            .to_request();
+        let response = test::call_service(&service, too_large).await;
+        assert_eq!(response.status(), 413);
+        let value: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(value["code"], json!(413));
+        assert_eq!(value["errors"][0]["reason"], json!("batchTooLarge"));
+
+        // Bodies that are too long are rejected before they are parsed
+        let too_long = TestRequest::post()
+            .uri("/?key=testkey")
+            .set_payload(format!("[\"{}\"]", " ".repeat(1000)))
+            .to_request();
+        let response = test::call_service(&service, too_long).await;
+        assert_eq!(response.status(), 413);
+        let value: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(value["errors"][0]["reason"], json!("batchTooLarge"));
+
+        // Self-selected downstream keys can't use the batch endpoint
+        let downstream = TestRequest::post()
+            .uri("/?key=firefox-downstream-test")
+            .set_json(json!(["7.7.7.7"]))
+            .to_request();
+        let response = test::call_service(&service, downstream).await;
+        assert_eq!(response.status(), 403);
+
+        let malformed = TestRequest::post()
+            .uri("/?key=testkey")
+            .set_payload("{\"ips\": []}")
+            .to_request();
+        let response = test::call_service(&service, malformed).await;
+        assert_eq!(response.status(), 400);
+        let value: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(value["errors"][0]["reason"], json!("parseError"));
+
+        // Batches count against the same rate limit as single lookups
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(EndpointState {
+                    api_keys: Arc::new(ApiKeys::new(vec![ApiKey {
+                        rate_limit: Some(RateLimit::new(0.001, 2)),
+                        ..ApiKey::new("limited")
+                    }])),
+                    geoip: Arc::new(GeoIp::builder().path("./GeoLite2-Country.mmdb").build()?),
+                    ..EndpointState::default()
+                }))
+                .route("/", web::get().to(super::get_country))
+                .route("/batch", web::post().to(super::get_country_batch)),
+        )
+        .await;
+        let single = TestRequest::get()
+            .uri("/?key=limited")
+            .insert_header(("x-forwarded-for", "7.7.7.7"))
+            .to_request();
+        assert_eq!(test::call_service(&service, single).await.status(), 200);
+        for status in [200, 429] {
+            let batch = TestRequest::post()
+                .uri("/batch?key=limited")
+                .set_json(json!(["7.7.7.7", "8.8.8.8"]))
+                .to_request();
+            let response = test::call_service(&service, batch).await;
+            assert_eq!(response.status(), status);
+        }
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_country_endpoint_ip_param() -> Result<(), Box<dyn std::error::Error>> {
+        let api_keys = Arc::new(ApiKeys::new(vec![
+            ApiKey::new("testkey"),
+            ApiKey {
+                capabilities: vec![Capability::Lookup],
+                ..ApiKey::new("adminkey")
+            },
+        ]));
+
+        let state = EndpointState {
+            api_keys,
+            geoip: Arc::new(GeoIp::builder().path("./GeoLite2-Country.mmdb").build()?),
+            ..EndpointState::default()
+        };
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .route("/", web::get().to(super::get_country)),
+        )
+        .await;
+
+        let admin_request = TestRequest::get()
+            .uri("/?key=adminkey&ip=7.7.7.7")
+            .insert_header(("x-forwarded-for", "127.0.0.2"))
+            .to_request();
+        let admin_value: serde_json::Value =
+            test::call_and_read_body_json(&service, admin_request).await;
+        assert_eq!(
+            *admin_value.get("country_code").unwrap(),
+            json!("US"),
+            "Keys with the lookup capability should locate the requested IP"
+        );
+
+        let invalid_ip_request = TestRequest::get()
+            .uri("/?key=adminkey&ip=nonsense")
+            .to_request();
+        let invalid_ip_response = test::call_service(&service, invalid_ip_request).await;
+        assert_eq!(invalid_ip_response.status(), 400);
+
+        for key in ["testkey", "firefox-downstream-foo"] {
+            let forbidden_request = TestRequest::get()
+                .uri(&format!("/?key={}&ip=7.7.7.7", key))
+                .insert_header(("x-forwarded-for", "7.7.7.7"))
+                .to_request();
+            let forbidden_response = test::call_service(&service, forbidden_request).await;
+            assert_eq!(
+                forbidden_response.status(),
+                403,
+                "Keys without the lookup capability may not use the ip parameter"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_country_endpoint_key_restrictions() -> Result<(), Box<dyn std::error::Error>> {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let metrics = Arc::new(Metrics::from_sink(
+            "test",
+            TestMetricSink { log: log.clone() },
+        ));
+        let api_keys = Arc::new(ApiKeys::new(vec![
+            ApiKey {
+                enabled: false,
+                ..ApiKey::new("disabled")
+            },
+            ApiKey {
+                expires: chrono::NaiveDate::from_ymd_opt(2020, 1, 1),
+                ..ApiKey::new("expired")
+            },
+            ApiKey {
+                endpoints: Some(vec![Endpoint::CountryBatch]),
+                ..ApiKey::new("batchonly")
+            },
+            // Listed keys take precedence over downstream key patterns
+            ApiKey {
+                enabled: false,
+                ..ApiKey::new("firefox-downstream-revoked")
+            },
+        ]));
+
+        let state = EndpointState {
+            api_keys,
+            geoip: Arc::new(GeoIp::builder().path("./GeoLite2-Country.mmdb").build()?),
+            metrics,
+            ..EndpointState::default()
+        };
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .route("/", web::get().to(super::get_country)),
+        )
+        .await;
+
+        let keys = [
+            ("disabled", 401),
+            ("expired", 401),
+            ("batchonly", 403),
+            ("firefox-downstream-revoked", 401),
+        ];
+        for (key, status) in keys {
+            let request = TestRequest::get()
+                .uri(&format!("/?key={}", key))
+                .insert_header(("x-forwarded-for", "7.7.7.7"))
+                .to_request();
+            let response = test::call_service(&service, request).await;
+            assert_eq!(response.status(), status, "unexpected status for {}", key);
+        }
+
+        assert_eq!(
+            *log.lock().unwrap().deref(),
+            vec![
+                "test.country:1|c|#api_key:disabled-key",
+                "test.country:1|c|#api_key:expired-key",
+                "test.country:1|c|#api_key:forbidden-key",
+                "test.country:1|c|#api_key:disabled-key",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_country_endpoint_rate_limit() -> Result<(), Box<dyn std::error::Error>> {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let metrics = Arc::new(Metrics::from_sink(
+            "test",
+            TestMetricSink { log: log.clone() },
+        ));
+        let api_keys = Arc::new(ApiKeys::new(vec![
+            ApiKey {
+                rate_limit: Some(RateLimit::new(0.5, 2)),
+                ..ApiKey::new("limited")
+            },
+            ApiKey::new("unlimited"),
+            // Named like the downstream key pattern
+            ApiKey {
+                rate_limit: Some(RateLimit::new(0.5, 1)),
+                ..ApiKey::new("firefox-downstream")
+            },
+        ]));
+
+        let state = EndpointState {
+            api_keys,
+            downstream_key_rate_limit: Some(RateLimit::new(0.1, 1)),
+            geoip: Arc::new(GeoIp::builder().path("./GeoLite2-Country.mmdb").build()?),
+            metrics,
+            ..EndpointState::default()
+        };
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .route("/", web::get().to(super::get_country)),
+        )
+        .await;
+
+        let keys = [
+            ("limited", 200),
+            ("limited", 200),
+            ("limited", 429),
+            ("unlimited", 200),
+            ("unlimited", 200),
+            ("unlimited", 200),
+            ("firefox-downstream-a", 200),
+            ("firefox-downstream-a", 429),
+            // Each downstream key has its own bucket
+            ("firefox-downstream-b", 200),
+            ("firefox-downstream-b", 429),
+            // Listed keys don't share buckets with downstream keys
+            ("firefox-downstream", 200),
+            ("firefox-downstream", 429),
+        ];
+        for (key, status) in keys {
+            let request = TestRequest::get()
+                .uri(&format!("/?key={}", key))
+                .insert_header(("x-forwarded-for", "7.7.7.7"))
+                .to_request();
+            let response = test::call_service(&service, request).await;
+            assert_eq!(response.status(), status, "unexpected status for {}", key);
+
+            if status == 429 {
+                let retry_after = response.headers().get("retry-after").unwrap().to_str()?;
+                assert!(retry_after.parse::<u64>()? > 0);
+                let body: serde_json::Value = test::read_body_json(response).await;
+                assert_eq!(
+                    body,
+                    json!({
+                        "code": 429,
+                        "message": "Too many requests",
+                        "errors": [{
+                            "domain": "geolocation",
+                            "reason": "rateLimitExceeded",
+                            "message": "Too many requests",
+                        }],
+                    })
+                );
+            }
+        }
+
+        let throttled: Vec<String> = log
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|line| line.starts_with("test.country_throttled"))
+            .cloned()
+            .collect();
+        assert_eq!(
+            throttled,
+            vec![
+                "test.country_throttled:1|c|#api_key:limited",
+                "test.country_throttled:1|c|#api_key:firefox-downstream",
+                "test.country_throttled:1|c|#api_key:firefox-downstream",
+                "test.country_throttled:1|c|#api_key:firefox-downstream",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_downstream_keys_dont_evict_listed_keys() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let api_keys = Arc::new(ApiKeys::new(vec![ApiKey {
+            rate_limit: Some(RateLimit::new(0.001, 1)),
+            ..ApiKey::new("limited")
+        }]));
+        let state = EndpointState {
+            api_keys,
+            downstream_key_limiter: Arc::new(RateLimiter::new(4)),
+            downstream_key_rate_limit: Some(RateLimit::new(0.001, 1)),
+            geoip: Arc::new(GeoIp::builder().path("./GeoLite2-Country.mmdb").build()?),
+            ..EndpointState::default()
+        };
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .route("/", web::get().to(super::get_country)),
+        )
+        .await;
+        let get = |key: String| {
+            TestRequest::get()
+                .uri(&format!("/?key={}", key))
+                .insert_header(("x-forwarded-for", "7.7.7.7"))
+                .to_request()
+        };
+
+        let response = test::call_service(&service, get("limited".to_owned())).await;
+        assert_eq!(response.status(), 200);
+        let response = test::call_service(&service, get("limited".to_owned())).await;
+        assert_eq!(response.status(), 429);
+
+        // Many more downstream keys than their limiter can track
+        for i in 0..20 {
+            let key = format!("firefox-downstream-{}", i);
+            let response = test::call_service(&service, get(key)).await;
+            assert_eq!(response.status(), 200);
+        }
+
+        let response = test::call_service(&service, get("limited".to_owned())).await;
+        assert_eq!(
+            response.status(),
+            429,
+            "the listed key's bucket should be kept"
+        );
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_country_endpoint_key_headers() -> Result<(), Box<dyn std::error::Error>> {
+        let api_keys = Arc::new(ApiKeys::new(vec![ApiKey::new("testkey")]));
+        let state = EndpointState {
+            api_keys,
+            geoip: Arc::new(GeoIp::builder().path("./GeoLite2-Country.mmdb").build()?),
+            ..EndpointState::default()
+        };
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .route("/", web::get().to(super::get_country)),
+        )
+        .await;
+
+        let requests = [
+            (vec![("Authorization", "Bearer testkey")], "/", 200),
+            (vec![("Authorization", "bearer testkey")], "/", 200),
+            (vec![("Authorization", "Basic testkey")], "/", 401),
+            (vec![("X-Api-Key", "testkey")], "/", 200),
+            (vec![("X-Api-Key", "wrongkey")], "/", 401),
+            // Headers take precedence over the query string
+            (vec![("X-Api-Key", "wrongkey")], "/?key=testkey", 401),
+            (vec![("X-Api-Key", "testkey")], "/?key=wrongkey", 200),
+            (
+                vec![
+                    ("Authorization", "Bearer testkey"),
+                    ("X-Api-Key", "wrongkey"),
+                ],
+                "/",
+                200,
+            ),
+        ];
+        for (headers, uri, status) in requests {
+            let mut request = TestRequest::get()
+                .uri(uri)
+                .insert_header(("x-forwarded-for", "7.7.7.7"));
+            for header in &headers {
+                request = request.insert_header(*header);
+            }
+            let response = test::call_service(&service, request.to_request()).await;
+            assert_eq!(
+                response.status(),
+                status,
+                "unexpected status for {:?} {}",
+                headers,
+                uri
+            );
+        }
+
+        Ok(())
+    }
 }
@@ -1,4 +1,4 @@
-use crate::{endpoints::EndpointState, utils::RequestClientIp};
+use crate::{endpoints::EndpointState, geoip::SpecialRange, utils::RequestClientIp};
 use actix_web::{web::Data, HttpRequest, HttpResponse};
 
 /// Show debugging information about the server comprising:
@@ -6,13 +6,20 @@ use actix_web::{web::Data, HttpRequest, HttpResponse};
 ///  * Request state,
 ///  * Current request's headers
 ///  * Calculated client IP for the current request
+///  * Whether that IP is private or reserved, rather than public
 ///
 /// This handler should be disabled in production servers.
 pub async fn debug_handler(req: HttpRequest, state: Data<EndpointState>) -> HttpResponse {
+    let client_ip = req.client_ip();
+    let client_ip_range = client_ip
+        .as_ref()
+        .ok()
+        .map(|ip| SpecialRange::of(*ip).map_or("public", SpecialRange::as_str));
     HttpResponse::Ok().body(format!(
-        "received headers: {:?}\n\nrequest state: {:?}\n\nclient ip: {:?}",
+        "received headers: {:?}\n\nrequest state: {:?}\n\nclient ip: {:?}\n\nclient ip range: {}",
         req.headers(),
         state,
-        req.client_ip()
+        client_ip,
+        client_ip_range.unwrap_or("unknown")
     ))
 }
@@ -1,46 +1,114 @@
-use crate::{endpoints::EndpointState, errors::ClassifyError};
+use crate::{
+    endpoints::EndpointState, errors::ClassifyError, geoip::DatabaseMetadata, settings::StalePolicy,
+};
 use actix_web::{web::Data, HttpResponse};
+use chrono::Utc;
 use serde_derive::Serialize;
 use std::{
     fs::File,
     io::Read,
     net::{IpAddr, Ipv4Addr},
+    sync::atomic::Ordering,
 };
 
-pub async fn lbheartbeat() -> HttpResponse {
+pub async fn lbheartbeat(app_data: Data<EndpointState>) -> HttpResponse {
+    // While shutting down, tell the load balancer to stop sending requests.
+    if app_data.draining.load(Ordering::SeqCst) {
+        return HttpResponse::ServiceUnavailable().body("");
+    }
     HttpResponse::Ok().body("")
 }
 
 #[derive(Serialize)]
 struct HeartbeatResponse {
     geoip: bool,
+    geoip_stale: bool,
+    geoip_databases: Vec<DatabaseStatus>,
+}
+
+#[derive(Serialize)]
+struct GeoIpResponse {
+    databases: Vec<DatabaseStatus>,
+}
+
+#[derive(Serialize)]
+struct DatabaseStatus {
+    #[serde(flatten)]
+    metadata: DatabaseMetadata,
+    stale: bool,
+}
+
+/// Describe each GeoIP database in use, including whether it is older than the
+/// configured maximum age.
+fn database_statuses(app_data: &EndpointState) -> Vec<DatabaseStatus> {
+    let now = Utc::now();
+    app_data
+        .geoip
+        .metadata()
+        .into_iter()
+        .map(|metadata| DatabaseStatus {
+            stale: app_data
+                .geoip_max_age_days
+                .map(|max_age_days| metadata.is_older_than(max_age_days, now))
+                .unwrap_or(false),
+            metadata,
+        })
+        .collect()
 }
 
 pub async fn heartbeat(app_data: Data<EndpointState>) -> Result<HttpResponse, ClassifyError> {
     let ip = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+    let geoip_databases = database_statuses(&app_data);
+    let geoip_stale = geoip_databases.iter().any(|status| status.stale);
+    // Load balancers poll this often, so only warn once per database build.
+    for status in geoip_databases
+        .iter()
+        .filter(|status| status.stale && app_data.geoip.note_stale(&status.metadata))
+    {
+        slog::warn!(
+            app_data.log,
+            "GeoIP database {} is older than {} days",
+            status.metadata.name,
+            app_data.geoip_max_age_days.unwrap_or_default()
+        );
+    }
+    let stale_fails = geoip_stale && app_data.geoip_stale_policy == StalePolicy::Fail;
 
     app_data
         .geoip
         .locate(ip)
         .and_then(|res| match res {
-            Some(country_info) => country_info
-                .country
-                .and_then(|country| country.iso_code)
+            Some(location) => location
+                .country_code
                 .map(|iso_code| Ok(!iso_code.is_empty()))
                 .unwrap_or(Ok(false)),
             None => Ok(false),
         })
         .or(Ok(false))
         .map(|res| {
-            let mut resp = if res {
+            let mut resp = if res && !stale_fails {
                 HttpResponse::Ok()
             } else {
                 HttpResponse::ServiceUnavailable()
             };
-            resp.json(HeartbeatResponse { geoip: res })
+            resp.json(HeartbeatResponse {
+                geoip: res,
+                geoip_stale,
+                geoip_databases,
+            })
         })
 }
 
+/// Describe the GeoIP databases this server is using.
+pub async fn geoip(app_data: Data<EndpointState>) -> HttpResponse {
+    let databases = database_statuses(&app_data);
+    if databases.is_empty() {
+        return HttpResponse::ServiceUnavailable()
+            .json(ClassifyError::new("No geoip database available"));
+    }
+    HttpResponse::Ok().json(GeoIpResponse { databases })
+}
+
 pub async fn version(app_data: Data<EndpointState>) -> HttpResponse {
     // Read the file or deliberately fail with a 500 if missing.
     let mut file = File::open(&app_data.version_file).unwrap();
@@ -53,21 +121,34 @@ pub async fn version(app_data: Data<EndpointState>) -> HttpResponse {
 
 #[cfg(test)]
 mod tests {
-    use crate::endpoints::EndpointState;
+    use crate::{endpoints::EndpointState, geoip::GeoIp, settings::StalePolicy};
     use actix_web::{
         http,
         test::{self, TestRequest},
         web::{self, Data},
         App,
     };
+    use serde_json::json;
+    use std::sync::{atomic::Ordering, Arc};
 
     #[actix_rt::test]
     async fn lbheartbeat() {
-        let service =
-            test::init_service(App::new().route("/", web::get().to(super::lbheartbeat))).await;
+        let state = EndpointState::default();
+        let draining = Arc::clone(&state.draining);
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .route("/", web::get().to(super::lbheartbeat)),
+        )
+        .await;
         let req = TestRequest::default().to_request();
         let res = test::call_service(&service, req).await;
         assert_eq!(res.status(), http::StatusCode::OK);
+
+        draining.store(true, Ordering::SeqCst);
+        let req = TestRequest::default().to_request();
+        let res = test::call_service(&service, req).await;
+        assert_eq!(res.status(), http::StatusCode::SERVICE_UNAVAILABLE);
     }
 
     #[actix_rt::test]
@@ -84,6 +165,107 @@ mod tests {
         assert_eq!(response.status(), http::StatusCode::SERVICE_UNAVAILABLE);
     }
 
+    #[actix_rt::test]
+    async fn heartbeat_reports_metadata() {
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(EndpointState {
+                    geoip: Arc::new(
+                        GeoIp::builder()
+                            .path("./GeoLite2-Country.mmdb")
+                            .build()
+                            .unwrap(),
+                    ),
+                    ..EndpointState::default()
+                }))
+                .route("/", web::get().to(super::heartbeat)),
+        )
+        .await;
+        let request = TestRequest::default().to_request();
+        let response = test::call_service(&service, request).await;
+        assert_eq!(response.status(), http::StatusCode::OK);
+        let value: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(value["geoip"], json!(true));
+        assert_eq!(value["geoip_stale"], json!(false));
+        assert_eq!(
+            value["geoip_databases"][0]["database_type"],
+            json!("GeoLite2-Country")
+        );
+    }
+
+    #[actix_rt::test]
+    async fn heartbeat_stale_database() {
+        for (policy, expected_status) in [
+            (StalePolicy::Warn, http::StatusCode::OK),
+            (StalePolicy::Fail, http::StatusCode::SERVICE_UNAVAILABLE),
+        ] {
+            let service = test::init_service(
+                App::new()
+                    .app_data(Data::new(EndpointState {
+                        geoip: Arc::new(
+                            GeoIp::builder()
+                                .path("./GeoLite2-Country.mmdb")
+                                .build()
+                                .unwrap(),
+                        ),
+                        // The database was built in the past, so it is always
+                        // older than a maximum age of zero days.
+                        geoip_max_age_days: Some(0),
+                        geoip_stale_policy: policy,
+                        ..EndpointState::default()
+                    }))
+                    .route("/", web::get().to(super::heartbeat)),
+            )
+            .await;
+            let request = TestRequest::default().to_request();
+            let response = test::call_service(&service, request).await;
+            assert_eq!(response.status(), expected_status);
+            let value: serde_json::Value = test::read_body_json(response).await;
+            assert_eq!(value["geoip_stale"], json!(true));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn geoip() {
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(EndpointState {
+                    geoip: Arc::new(
+                        GeoIp::builder()
+                            .path("./GeoLite2-Country.mmdb")
+                            .build()
+                            .unwrap(),
+                    ),
+                    ..EndpointState::default()
+                }))
+                .route("/", web::get().to(super::geoip)),
+        )
+        .await;
+        let request = TestRequest::default().to_request();
+        let value: serde_json::Value = test::call_and_read_body_json(&service, request).await;
+        let database = &value["databases"][0];
+        assert_eq!(database["name"], json!("GeoLite2-Country"));
+        assert_eq!(database["database_type"], json!("GeoLite2-Country"));
+        assert_eq!(database["ip_version"], json!(6));
+        assert_eq!(database["stale"], json!(false));
+        assert!(database["build_epoch"].is_u64());
+        assert!(database["node_count"].is_u64());
+        assert!(database["languages"].is_array());
+    }
+
+    #[actix_rt::test]
+    async fn geoip_without_database() {
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(EndpointState::default()))
+                .route("/", web::get().to(super::geoip)),
+        )
+        .await;
+        let request = TestRequest::default().to_request();
+        let response = test::call_service(&service, request).await;
+        assert_eq!(response.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+    }
+
     #[actix_rt::test]
     async fn version() -> Result<(), Box<dyn std::error::Error>> {
         let service = test::init_service(
@@ -3,30 +3,82 @@ pub mod classify;
 pub mod country;
 pub mod debug;
 pub mod dockerflow;
-use crate::{geoip::GeoIp, APP_NAME};
-use std::{collections::HashSet, default::Default, path::PathBuf, sync::Arc};
+use crate::{
+    geoip::GeoIp,
+    keys::{ApiKeys, DownstreamKeys},
+    metrics::{Metrics, TagValues},
+    ratelimit::{ClientRateLimits, RateLimit, RateLimiter},
+    settings::{ForwardedHeader, InvalidHopPolicy, StalePolicy},
+    APP_NAME,
+};
+use opentelemetry_sdk::trace::SdkTracer;
+use std::{
+    default::Default,
+    path::PathBuf,
+    sync::{atomic::AtomicBool, Arc},
+};
 
 #[derive(Clone, Debug)]
 pub struct EndpointState {
-    pub api_keys_hashset: HashSet<String>,
+    pub api_keys: Arc<ApiKeys>,
+    /// Rate limits keys from the API keys file.
+    pub api_key_limiter: Arc<RateLimiter<String>>,
+    /// Bounds the number of API keys reported in metrics.
+    pub api_key_tags: Arc<TagValues>,
+    pub client_rate_limits: Arc<ClientRateLimits>,
+    pub country_batch_max_size: usize,
+    /// Set when the server is shutting down, so that `__lbheartbeat__` fails.
+    pub draining: Arc<AtomicBool>,
+    /// Rate limits self-selected downstream keys. These are kept apart from
+    /// listed keys, so that making up many downstream keys can't push the
+    /// buckets of listed keys out.
+    pub downstream_key_limiter: Arc<RateLimiter<String>>,
+    pub downstream_key_rate_limit: Option<RateLimit>,
+    pub downstream_keys: DownstreamKeys,
+    pub extended_classification: bool,
+    pub forwarded_header: ForwardedHeader,
     pub geoip: Arc<GeoIp>,
+    pub geoip_max_age_days: Option<u64>,
+    pub geoip_stale_policy: StalePolicy,
+    pub invalid_hop_policy: InvalidHopPolicy,
     pub trusted_proxies: Vec<ipnet::IpNet>,
+    pub trusted_proxy_hops: Option<usize>,
+    pub trusted_client_ip_header: Option<String>,
+    pub trusted_client_ip_header_peers: Vec<ipnet::IpNet>,
+    /// Records request spans, if tracing is enabled.
+    pub tracer: Option<SdkTracer>,
     pub log: slog::Logger,
-    pub metrics: Arc<cadence::StatsdClient>,
+    pub log_redact_api_keys: bool,
+    pub metrics: Arc<Metrics>,
     pub version_file: PathBuf,
 }
 
 impl Default for EndpointState {
     fn default() -> Self {
         EndpointState {
-            api_keys_hashset: HashSet::new(),
+            api_keys: Arc::new(ApiKeys::default()),
+            api_key_limiter: Arc::new(RateLimiter::default()),
+            api_key_tags: Arc::new(TagValues::default()),
+            client_rate_limits: Arc::new(ClientRateLimits::default()),
+            country_batch_max_size: 1000,
+            draining: Arc::new(AtomicBool::new(false)),
+            downstream_key_limiter: Arc::new(RateLimiter::default()),
+            downstream_key_rate_limit: None,
+            downstream_keys: DownstreamKeys::default(),
+            extended_classification: false,
+            forwarded_header: ForwardedHeader::default(),
             trusted_proxies: Vec::default(),
+            trusted_proxy_hops: None,
+            trusted_client_ip_header: None,
+            trusted_client_ip_header_peers: Vec::default(),
+            tracer: None,
             geoip: Arc::new(GeoIp::default()),
+            geoip_max_age_days: None,
+            geoip_stale_policy: StalePolicy::default(),
+            invalid_hop_policy: InvalidHopPolicy::default(),
             log: slog::Logger::root(slog::Discard, slog::o!()),
-            metrics: Arc::new(cadence::StatsdClient::from_sink(
-                APP_NAME,
-                cadence::NopMetricSink,
-            )),
+            log_redact_api_keys: false,
+            metrics: Arc::new(Metrics::from_sink(APP_NAME, cadence::NopMetricSink)),
             version_file: "./version.json".into(),
         }
     }
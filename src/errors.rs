@@ -50,5 +50,6 @@ impl_from_error!(actix_web::http::header::ToStrError);
 impl_from_error!(envy::Error);
 impl_from_error!(ipnet::AddrParseError);
 impl_from_error!(maxminddb::MaxMindDBError);
+impl_from_error!(serde_json::Error);
 impl_from_error!(std::io::Error);
 impl_from_error!(std::net::AddrParseError);
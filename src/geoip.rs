@@ -1,11 +1,345 @@
-use crate::errors::ClassifyError;
-use cadence::{prelude::*, StatsdClient};
+use crate::{
+    errors::ClassifyError,
+    metrics::{Metrics, TagValues},
+    overrides::Overrides,
+    reload::Reloadable,
+};
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use lru::LruCache;
 use maxminddb::{self, geoip2, MaxMindDBError};
-use std::{fmt, net::IpAddr, path::PathBuf, sync::Arc};
+use serde_derive::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+
+/// The result of a geolocation lookup.
+///
+/// This owns its data rather than borrowing from the database, so that the
+/// database can be swapped out while results are still in use. Fields other
+/// than the country are only filled in from City databases.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Location {
+    /// ISO 3166-1 alpha-2 code of the country, like "US".
+    pub country_code: Option<String>,
+    /// English name of the country, like "United States".
+    pub country_name: Option<String>,
+    /// Two letter continent code, like "NA".
+    pub continent_code: Option<String>,
+    /// ISO 3166-2 code of the largest subdivision within the country, like
+    /// "CA" for California.
+    pub subdivision_code: Option<String>,
+    /// English name of the city, like "Mountain View".
+    pub city_name: Option<String>,
+    /// IANA time zone, like "America/Los_Angeles".
+    pub time_zone: Option<String>,
+    pub coordinates: Option<Coordinates>,
+}
+
+/// An approximate position. Latitude and longitude are rounded to one decimal
+/// place, which is roughly 10km.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Coordinates {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// The radius in kilometers around the position that the address is
+    /// likely to be within, as reported by the database.
+    pub accuracy_radius: Option<u16>,
+}
+
+impl Coordinates {
+    fn coarse(latitude: f64, longitude: f64, accuracy_radius: Option<u16>) -> Self {
+        Self {
+            latitude: (latitude * 10.0).round() / 10.0,
+            longitude: (longitude * 10.0).round() / 10.0,
+            accuracy_radius,
+        }
+    }
+}
+
+fn english_name(names: Option<BTreeMap<&str, &str>>) -> Option<String> {
+    names
+        .and_then(|names| names.get("en").copied())
+        .map(str::to_owned)
+}
+
+impl<'a> From<geoip2::Country<'a>> for Location {
+    fn from(country_info: geoip2::Country<'a>) -> Self {
+        let country = country_info.country;
+        Self {
+            country_code: country
+                .as_ref()
+                .and_then(|country| country.iso_code)
+                .map(str::to_owned),
+            country_name: english_name(country.and_then(|country| country.names)),
+            continent_code: country_info
+                .continent
+                .and_then(|continent| continent.code)
+                .map(str::to_owned),
+            ..Self::default()
+        }
+    }
+}
+
+impl<'a> From<geoip2::City<'a>> for Location {
+    fn from(city_info: geoip2::City<'a>) -> Self {
+        let country = city_info.country;
+        let location = city_info.location;
+        Self {
+            country_code: country
+                .as_ref()
+                .and_then(|country| country.iso_code)
+                .map(str::to_owned),
+            country_name: english_name(country.and_then(|country| country.names)),
+            continent_code: city_info
+                .continent
+                .and_then(|continent| continent.code)
+                .map(str::to_owned),
+            subdivision_code: city_info
+                .subdivisions
+                .and_then(|subdivisions| subdivisions.into_iter().next())
+                .and_then(|subdivision| subdivision.iso_code)
+                .map(str::to_owned),
+            city_name: english_name(city_info.city.and_then(|city| city.names)),
+            time_zone: location
+                .as_ref()
+                .and_then(|location| location.time_zone)
+                .map(str::to_owned),
+            coordinates: location.and_then(|location| {
+                Some(Coordinates::coarse(
+                    location.latitude?,
+                    location.longitude?,
+                    location.accuracy_radius,
+                ))
+            }),
+        }
+    }
+}
+
+/// Addresses that aren't on the public internet, so have no location. They
+/// are classified before looking in the databases.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SpecialRange {
+    /// Networks used within organizations: RFC 1918, unique local IPv6
+    /// addresses, carrier-grade NAT (RFC 6598) and local-use NAT64 (RFC 8215).
+    Private,
+    /// Loopback, link-local, documentation, benchmarking, multicast,
+    /// unspecified and other reserved addresses.
+    Reserved,
+}
+
+impl SpecialRange {
+    /// The range that `ip` is in, if it isn't a public address.
+    pub fn of(ip: IpAddr) -> Option<Self> {
+        match ip {
+            IpAddr::V4(ip) => Self::of_v4(ip),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => Self::of_v4(ip),
+                None => Self::of_v6(ip),
+            },
+        }
+    }
+
+    fn of_v4(ip: Ipv4Addr) -> Option<Self> {
+        let [a, b, c, _] = ip.octets();
+        if ip.is_private() || (a == 100 && b & 0xc0 == 64) {
+            Some(SpecialRange::Private)
+        } else if ip.is_loopback()
+            || ip.is_link_local()
+            || ip.is_documentation()
+            || ip.is_unspecified()
+            || ip.is_multicast()
+            || a == 0
+            || a >= 240
+            // IETF protocol assignments
+            || (a == 192 && b == 0 && c == 0)
+            // Benchmarking
+            || (a == 198 && b & 0xfe == 18)
+        {
+            Some(SpecialRange::Reserved)
+        } else {
+            None
+        }
+    }
+
+    fn of_v6(ip: Ipv6Addr) -> Option<Self> {
+        let segments = ip.segments();
+        let [first, second, third, ..] = segments;
+        if first & 0xfe00 == 0xfc00
+            // Local-use NAT64
+            || (first == 0x64 && second == 0xff9b && third == 1)
+        {
+            Some(SpecialRange::Private)
+        } else if ip.is_loopback()
+            || ip.is_unspecified()
+            || ip.is_multicast()
+            || first & 0xffc0 == 0xfe80
+            || (first == 0x2001 && second == 0x0db8)
+            // Well-known NAT64 prefix
+            || segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0]
+            // Discard-only
+            || segments[..4] == [0x100, 0, 0, 0]
+            // Benchmarking
+            || (first == 0x2001 && second == 2 && third == 0)
+        {
+            Some(SpecialRange::Reserved)
+        } else {
+            None
+        }
+    }
+
+    /// The reason reported for addresses in this range, like "private".
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SpecialRange::Private => "private",
+            SpecialRange::Reserved => "reserved",
+        }
+    }
+}
+
+/// Information about the build of the GeoIP database in use.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct DatabaseMetadata {
+    /// The name this server uses for the database in metrics.
+    pub name: String,
+    pub database_type: String,
+    /// Seconds since the Unix epoch at which the database was built.
+    pub build_epoch: u64,
+    pub ip_version: u16,
+    pub languages: Vec<String>,
+    pub node_count: u32,
+}
+
+impl DatabaseMetadata {
+    /// The time at which the database was built.
+    pub fn build_time(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp(self.build_epoch as i64, 0).unwrap_or_default()
+    }
+
+    /// Whether the database was built more than `max_age_days` days before `now`.
+    pub fn is_older_than(&self, max_age_days: u64, now: DateTime<Utc>) -> bool {
+        now.signed_duration_since(self.build_time()) > chrono::Duration::days(max_age_days as i64)
+    }
+}
+
+impl DatabaseMetadata {
+    fn new(name: &str, metadata: &maxminddb::Metadata) -> Self {
+        Self {
+            name: name.to_owned(),
+            database_type: metadata.database_type.clone(),
+            build_epoch: metadata.build_epoch,
+            ip_version: metadata.ip_version,
+            languages: metadata.languages.clone(),
+            node_count: metadata.node_count,
+        }
+    }
+}
+
+type Reader = maxminddb::Reader<Vec<u8>>;
+
+/// One of the databases consulted by `GeoIp`.
+struct Database {
+    /// Used to tag metrics with the database that answered. This is the file
+    /// name without its extension, like "GeoLite2-Country".
+    name: String,
+    path: PathBuf,
+    reader: RwLock<Arc<Reader>>,
+    /// The build epoch of the last version of this database that was
+    /// reported as stale, or zero if none was.
+    stale_build_epoch: AtomicU64,
+}
+
+impl Database {
+    fn open(path: PathBuf) -> Result<Self, ClassifyError> {
+        let reader = Reader::open_readfile(&path)?;
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.to_string_lossy().into_owned());
+        Ok(Self {
+            name,
+            path,
+            reader: RwLock::new(Arc::new(reader)),
+            stale_build_epoch: AtomicU64::new(0),
+        })
+    }
+
+    fn reader(&self) -> Arc<Reader> {
+        self.reader
+            .read()
+            .expect("geoip reader lock poisoned")
+            .clone()
+    }
+
+    fn reload(&self) -> Result<(), ClassifyError> {
+        let reader = Reader::open_readfile(&self.path)
+            .map_err(|err| ClassifyError::from_source(self.path.display(), err))?;
+        *self.reader.write().expect("geoip reader lock poisoned") = Arc::new(reader);
+        Ok(())
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Result<Option<Location>, MaxMindDBError> {
+        // Hold on to the current reader for the whole lookup, so that a
+        // concurrent reload doesn't pull it out from under us.
+        let reader = self.reader();
+
+        // City databases are a superset of Country databases, so decode
+        // whichever record type has the most detail.
+        let lookup = if reader.metadata.database_type.contains("City") {
+            reader
+                .lookup::<Option<geoip2::City>>(ip)
+                .map(|city_info| city_info.map(Location::from))
+        } else {
+            reader
+                .lookup::<Option<geoip2::Country>>(ip)
+                .map(|country_info| country_info.map(Location::from))
+        };
+
+        match lookup {
+            Err(MaxMindDBError::AddressNotFoundError(_)) => Ok(None),
+            result => result,
+        }
+    }
+}
+
+/// Recent lookup results, keyed by the network containing the address that was
+/// looked up. Each result is stored with the name of the source that answered.
+struct LocationCache {
+    entries: Mutex<LruCache<IpNet, (Option<Location>, String)>>,
+    ipv4_prefix_len: u8,
+    ipv6_prefix_len: u8,
+}
+
+impl LocationCache {
+    fn key(&self, ip: IpAddr) -> IpNet {
+        let prefix_len = match ip {
+            IpAddr::V4(_) => self.ipv4_prefix_len,
+            IpAddr::V6(_) => self.ipv6_prefix_len,
+        };
+        // Prefix lengths are validated when the cache is built.
+        IpNet::new(ip, prefix_len)
+            .expect("valid cache prefix length")
+            .trunc()
+    }
+}
 
 pub struct GeoIp {
-    reader: Option<maxminddb::Reader<Vec<u8>>>,
-    metrics: Arc<StatsdClient>,
+    overrides: Option<Overrides>,
+    databases: Vec<Database>,
+    cache: Option<LocationCache>,
+    metrics: Arc<Metrics>,
+    country_tags: TagValues,
+    /// Reported for addresses in `SpecialRange::Private`.
+    private_location: Option<Location>,
+    log: slog::Logger,
 }
 
 impl GeoIp {
@@ -13,34 +347,193 @@ impl GeoIp {
         GeoIpBuilder::default()
     }
 
-    pub fn locate(&self, ip: IpAddr) -> Result<Option<geoip2::Country>, ClassifyError> {
-        self.reader
+    /// Describe the databases currently in use, in the order they are consulted.
+    pub fn metadata(&self) -> Vec<DatabaseMetadata> {
+        self.databases
+            .iter()
+            .map(|database| DatabaseMetadata::new(&database.name, &database.reader().metadata))
+            .collect()
+    }
+
+    /// Record that the database described by `metadata` is stale. This only
+    /// returns true the first time it is called for each build of that
+    /// database, so that frequent health checks can warn about it once.
+    pub fn note_stale(&self, metadata: &DatabaseMetadata) -> bool {
+        self.databases
+            .iter()
+            .find(|database| database.name == metadata.name)
+            .map(|database| {
+                database
+                    .stale_build_epoch
+                    .swap(metadata.build_epoch, Ordering::SeqCst)
+                    != metadata.build_epoch
+            })
+            .unwrap_or(false)
+    }
+
+    /// Find the location of `ip`. Local overrides take priority, then
+    /// addresses in a `SpecialRange` are given the default location for that
+    /// range, if any. After that the first database that knows which country
+    /// it is in is used.
+    pub fn locate(&self, ip: IpAddr) -> Result<Option<Location>, ClassifyError> {
+        // Overrides and special ranges are checked before the cache, whose
+        // networks may cover addresses that they don't.
+        let range = SpecialRange::of(ip);
+        let overridden = self.overrides.as_ref().and_then(|o| o.lookup(ip));
+        let (location, source) = match (overridden, range, &self.cache) {
+            (Some(location), _, _) => (Some(location), "override".to_owned()),
+            (None, Some(range), _) => self.special_location(range),
+            (None, None, Some(cache)) => {
+                let key = cache.key(ip);
+                let cached = cache
+                    .entries
+                    .lock()
+                    .expect("geoip cache lock poisoned")
+                    .get(&key)
+                    .cloned();
+                match cached {
+                    Some(cached) => {
+                        self.metrics.incr_with_tags("geoip_cache_hit").send();
+                        cached
+                    }
+                    None => {
+                        self.metrics.incr_with_tags("geoip_cache_miss").send();
+                        let result = self.lookup(ip)?;
+                        cache
+                            .entries
+                            .lock()
+                            .expect("geoip cache lock poisoned")
+                            .put(key, result.clone());
+                        result
+                    }
+                }
+            }
+            (None, None, None) => self.lookup(ip)?,
+        };
+
+        // Send a metrics ping about the geolocation result
+        let iso_code = location
             .as_ref()
-            .ok_or_else(|| ClassifyError::new("No geoip database available"))?
-            .lookup(ip)
-            .map(|country_info: Option<geoip2::Country>| {
-                // Send a metrics ping about the geolocation result
-                let iso_code = country_info
-                    .clone()
-                    .and_then(|country_info| country_info.country)
-                    .and_then(|country| country.iso_code);
+            .and_then(|location| location.country_code.as_deref());
+        self.metrics
+            .incr_with_tags("location")
+            .with_tag(
+                "country",
+                self.country_tags.get(iso_code.unwrap_or("unknown")),
+            )
+            .with_tag("database", &source)
+            .with_tag("reason", range.map_or("public", SpecialRange::as_str))
+            .send();
+        Ok(location)
+    }
+
+    /// The default location for addresses in `range`, along with the name of
+    /// the source that answered. The databases aren't consulted.
+    fn special_location(&self, range: SpecialRange) -> (Option<Location>, String) {
+        let location = match range {
+            SpecialRange::Private => self.private_location.clone(),
+            SpecialRange::Reserved => None,
+        };
+        (location, "none".to_owned())
+    }
+
+    /// Find the location of `ip` in the databases, without using the cache,
+    /// along with the name of the one that answered.
+    fn lookup(&self, ip: IpAddr) -> Result<(Option<Location>, String), ClassifyError> {
+        if self.databases.is_empty() {
+            return Err(ClassifyError::new("No geoip database available"));
+        }
+
+        // A database that can't decode the record is skipped like one that
+        // doesn't know the address, but is reported if no other database can
+        // answer either.
+        let mut error = None;
+        for database in &self.databases {
+            match database.lookup(ip) {
+                Ok(Some(location)) if location.country_code.is_some() => {
+                    return Ok((Some(location), database.name.clone()));
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    slog::warn!(
+                        self.log,
+                        "Could not look up {} in {}. {}",
+                        ip,
+                        database.name,
+                        err
+                    );
+                    error.get_or_insert(err);
+                }
+            }
+        }
+
+        match error {
+            Some(err) => Err(err.into()),
+            None => Ok((None, "none".to_owned())),
+        }
+    }
+}
+
+impl Reloadable for GeoIp {
+    fn name(&self) -> &'static str {
+        "geoip"
+    }
+
+    fn watched_paths(&self) -> Vec<PathBuf> {
+        self.overrides
+            .iter()
+            .map(|overrides| overrides.path().to_owned())
+            .chain(self.databases.iter().map(|database| database.path.clone()))
+            .collect()
+    }
+
+    /// Open the override and database files again and swap them in. Lookups
+    /// that are already running finish against the previous versions. A file
+    /// that fails to load keeps its previous version, without affecting the
+    /// others, and is reported with a `geoip_reload_error` metric. This only
+    /// fails if no file could be loaded, so that one broken file doesn't make
+    /// every later check reload all of them again.
+    fn reload(&self) -> Result<(), ClassifyError> {
+        let results: Vec<(&str, Result<(), ClassifyError>)> = self
+            .overrides
+            .iter()
+            .map(|overrides| ("override", overrides.reload()))
+            .chain(
+                self.databases
+                    .iter()
+                    .map(|database| (database.name.as_str(), database.reload())),
+            )
+            .collect();
+        // Cached results may have come from the previous versions.
+        if let Some(cache) = &self.cache {
+            cache
+                .entries
+                .lock()
+                .expect("geoip cache lock poisoned")
+                .clear();
+        }
+
+        let mut errors = Vec::new();
+        for (name, result) in &results {
+            if let Err(err) = result {
+                slog::error!(
+                    self.log,
+                    "Failed to reload {}, keeping the previous version. {}",
+                    name,
+                    err
+                );
                 self.metrics
-                    .incr_with_tags("location")
-                    .with_tag("country", iso_code.unwrap_or("unknown"))
+                    .incr_with_tags("geoip_reload_error")
+                    .with_tag("file", name)
                     .send();
-                country_info
-            })
-            .or_else(|err| match err {
-                MaxMindDBError::AddressNotFoundError(_) => {
-                    self.metrics
-                        .incr_with_tags("location")
-                        .with_tag("country", "unknown")
-                        .send();
-                    Ok(None)
-                }
-                _ => Err(err),
-            })
-            .map_err(|err| err.into())
+                errors.push(err.to_string());
+            }
+        }
+        if errors.len() < results.len() {
+            Ok(())
+        } else {
+            Err(ClassifyError::new(errors.join(", ")))
+        }
     }
 }
 
@@ -53,14 +546,12 @@ impl Default for GeoIp {
 // // maxminddb reader doesn't implement Debug, so we can't use #[derive(Debug)] on GeoIp.
 impl fmt::Debug for GeoIp {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let paths: Vec<_> = self.databases.iter().map(|db| &db.path).collect();
         write!(
             fmt,
-            "GeoIpActor {{ reader: {}, metrics: {:?} }}",
-            if self.reader.is_some() {
-                "Some(...)"
-            } else {
-                "None"
-            },
+            "GeoIpActor {{ overrides: {:?}, databases: {:?}, metrics: {:?} }}",
+            self.overrides.as_ref().map(|overrides| overrides.path()),
+            paths,
             self.metrics
         )?;
         Ok(())
@@ -69,40 +560,135 @@ impl fmt::Debug for GeoIp {
 
 #[derive(Clone, Debug, Default)]
 pub struct GeoIpBuilder {
-    path: Option<PathBuf>,
-    metrics: Option<Arc<StatsdClient>>,
+    overrides_path: Option<PathBuf>,
+    paths: Vec<PathBuf>,
+    cache_size: usize,
+    cache_prefix_lens: Option<(u8, u8)>,
+    metrics: Option<Arc<Metrics>>,
+    max_tag_values: Option<usize>,
+    private_country: Option<(String, String)>,
+    log: Option<slog::Logger>,
 }
 
 impl GeoIpBuilder {
+    /// Add a database. Databases are consulted in the order they are added.
     pub fn path<P>(mut self, path: P) -> Self
     where
         P: Into<PathBuf>,
     {
-        self.path = Some(path.into());
+        self.paths.push(path.into());
+        self
+    }
+
+    /// Add several databases. Databases are consulted in the order they are added.
+    pub fn paths<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.paths.extend(paths.into_iter().map(Into::into));
         self
     }
 
-    pub fn metrics(mut self, metrics: Arc<StatsdClient>) -> Self {
+    /// Use a file of local overrides, which take priority over the databases.
+    /// See `Overrides` for the format.
+    pub fn overrides_path<P>(mut self, path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.overrides_path = Some(path.into());
+        self
+    }
+
+    /// Cache up to `size` lookup results. A size of 0, the default, disables
+    /// the cache.
+    pub fn cache_size(mut self, size: usize) -> Self {
+        self.cache_size = size;
+        self
+    }
+
+    /// Share cached results between all addresses in the same network of the
+    /// given size, instead of caching each address separately. This trades
+    /// accuracy for a higher hit rate.
+    pub fn cache_prefix_lens(mut self, ipv4_prefix_len: u8, ipv6_prefix_len: u8) -> Self {
+        self.cache_prefix_lens = Some((ipv4_prefix_len, ipv6_prefix_len));
+        self
+    }
+
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
         self.metrics = Some(metrics);
         self
     }
 
+    /// Where to log files that fail to reload and records that fail to decode.
+    pub fn log(mut self, log: slog::Logger) -> Self {
+        self.log = Some(log);
+        self
+    }
+
+    /// Report at most `max` distinct countries in metrics. Overrides can
+    /// report any country code, so this keeps the number of series bounded.
+    pub fn max_tag_values(mut self, max: usize) -> Self {
+        self.max_tag_values = Some(max);
+        self
+    }
+
+    /// Report addresses in `SpecialRange::Private` as being in the country
+    /// with this code and English name, instead of having no location.
+    pub fn private_country<S: Into<String>>(mut self, country_code: S, country_name: S) -> Self {
+        self.private_country = Some((country_code.into(), country_name.into()));
+        self
+    }
+
     pub fn build(self) -> Result<GeoIp, ClassifyError> {
-        let reader = match self.path {
-            Some(path) => Some(maxminddb::Reader::open_readfile(path)?),
-            None => None,
+        let cache = if let Some(cache_size) = NonZeroUsize::new(self.cache_size) {
+            let (ipv4_prefix_len, ipv6_prefix_len) = self.cache_prefix_lens.unwrap_or((32, 128));
+            if ipv4_prefix_len > 32 || ipv6_prefix_len > 128 {
+                return Err(ClassifyError::new(format!(
+                    "Invalid geoip cache prefix lengths /{} and /{}",
+                    ipv4_prefix_len, ipv6_prefix_len
+                )));
+            }
+            Some(LocationCache {
+                entries: Mutex::new(LruCache::new(cache_size)),
+                ipv4_prefix_len,
+                ipv6_prefix_len,
+            })
+        } else {
+            None
         };
-        let metrics = self.metrics.unwrap_or_else(|| {
-            Arc::new(StatsdClient::from_sink("default", cadence::NopMetricSink))
-        });
-        Ok(GeoIp { reader, metrics })
+        let overrides = self.overrides_path.map(Overrides::open).transpose()?;
+        let databases = self
+            .paths
+            .into_iter()
+            .map(Database::open)
+            .collect::<Result<_, _>>()?;
+        let metrics = self
+            .metrics
+            .unwrap_or_else(|| Arc::new(Metrics::from_sink("default", cadence::NopMetricSink)));
+        Ok(GeoIp {
+            overrides,
+            databases,
+            cache,
+            metrics,
+            country_tags: self.max_tag_values.map(TagValues::new).unwrap_or_default(),
+            private_location: self
+                .private_country
+                .map(|(country_code, country_name)| Location {
+                    country_code: Some(country_code),
+                    country_name: Some(country_name),
+                    ..Location::default()
+                }),
+            log: self
+                .log
+                .unwrap_or_else(|| slog::Logger::root(slog::Discard, slog::o!())),
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::metrics::tests::TestMetricSink;
-    use cadence::StatsdClient;
+    use crate::metrics::{tests::TestMetricSink, Metrics};
     use std::{
         ops::Deref,
         sync::{Arc, Mutex},
@@ -116,33 +702,487 @@ mod tests {
 
         let ip = "7.7.7.7".parse()?;
         let rv = geoip.locate(ip).unwrap().unwrap();
-        assert_eq!(rv.country.unwrap().iso_code.unwrap(), "US");
+        assert_eq!(rv.country_code.as_deref(), Some("US"));
+        assert_eq!(rv.country_name.as_deref(), Some("United States"));
         Ok(())
     }
 
     #[test]
     fn test_geoip_sends_metrics() -> Result<(), Box<dyn std::error::Error>> {
         let log = Arc::new(Mutex::new(Vec::new()));
-        let metrics = Arc::new(StatsdClient::from_sink(
+        let metrics = Arc::new(Metrics::from_sink(
+            "test",
+            TestMetricSink { log: log.clone() },
+        ));
+        let geoip = super::GeoIp::builder()
+            .path("./GeoLite2-Country.mmdb")
+            .metrics(metrics)
+            .build()?;
+
+        geoip.locate("7.7.7.7".parse()?)?;
+        geoip.locate("8.8.8.8".parse()?)?;
+
+        assert_eq!(
+            *log.lock().unwrap().deref(),
+            vec![
+                "test.location:1|c|#country:US,database:GeoLite2-Country,reason:public",
+                "test.location:1|c|#country:unknown,database:none,reason:public",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_geoip_limits_country_tags() -> Result<(), Box<dyn std::error::Error>> {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let metrics = Arc::new(Metrics::from_sink(
             "test",
             TestMetricSink { log: log.clone() },
         ));
         let geoip = super::GeoIp::builder()
             .path("./GeoLite2-Country.mmdb")
             .metrics(metrics)
+            .max_tag_values(1)
             .build()?;
 
         geoip.locate("7.7.7.7".parse()?)?;
-        geoip.locate("127.0.0.1".parse()?)?;
+        geoip.locate("8.8.8.8".parse()?)?;
+        geoip.locate("7.7.7.7".parse()?)?;
 
         assert_eq!(
             *log.lock().unwrap().deref(),
             vec![
-                "test.location:1|c|#country:US",
-                "test.location:1|c|#country:unknown",
+                "test.location:1|c|#country:US,database:GeoLite2-Country,reason:public",
+                "test.location:1|c|#country:other,database:none,reason:public",
+                "test.location:1|c|#country:US,database:GeoLite2-Country,reason:public",
             ]
         );
 
         Ok(())
     }
+
+    #[test]
+    fn test_geoip_uses_databases_in_order() -> Result<(), Box<dyn std::error::Error>> {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let metrics = Arc::new(Metrics::from_sink(
+            "test",
+            TestMetricSink { log: log.clone() },
+        ));
+        let country_first = super::GeoIp::builder()
+            .paths(["./GeoLite2-Country.mmdb", "./GeoLite2-City.mmdb"])
+            .metrics(metrics.clone())
+            .build()?;
+        let city_first = super::GeoIp::builder()
+            .paths(["./GeoLite2-City.mmdb", "./GeoLite2-Country.mmdb"])
+            .metrics(metrics)
+            .build()?;
+
+        let rv = country_first.locate("7.7.7.7".parse()?)?.unwrap();
+        assert_eq!(rv.country_code.as_deref(), Some("US"));
+        let rv = city_first.locate("7.7.7.7".parse()?)?.unwrap();
+        assert_eq!(rv.country_code.as_deref(), Some("US"));
+        assert!(country_first.locate("8.8.8.8".parse()?)?.is_none());
+
+        assert_eq!(
+            *log.lock().unwrap().deref(),
+            vec![
+                "test.location:1|c|#country:US,database:GeoLite2-Country,reason:public",
+                "test.location:1|c|#country:US,database:GeoLite2-City,reason:public",
+                "test.location:1|c|#country:unknown,database:none,reason:public",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_special_ranges() {
+        use super::SpecialRange;
+
+        for (ip, expected) in [
+            ("7.7.7.7", None),
+            ("8.8.8.8", None),
+            ("2001:4860::1", None),
+            ("10.1.2.3", Some(SpecialRange::Private)),
+            ("172.16.0.1", Some(SpecialRange::Private)),
+            ("192.168.1.1", Some(SpecialRange::Private)),
+            ("100.64.0.1", Some(SpecialRange::Private)),
+            ("100.127.255.255", Some(SpecialRange::Private)),
+            ("100.128.0.1", None),
+            ("fd00::1", Some(SpecialRange::Private)),
+            ("::ffff:10.0.0.1", Some(SpecialRange::Private)),
+            ("127.0.0.1", Some(SpecialRange::Reserved)),
+            ("169.254.1.1", Some(SpecialRange::Reserved)),
+            ("192.0.2.1", Some(SpecialRange::Reserved)),
+            ("198.51.100.1", Some(SpecialRange::Reserved)),
+            ("203.0.113.1", Some(SpecialRange::Reserved)),
+            ("0.0.0.0", Some(SpecialRange::Reserved)),
+            ("255.255.255.255", Some(SpecialRange::Reserved)),
+            ("::1", Some(SpecialRange::Reserved)),
+            ("fe80::1", Some(SpecialRange::Reserved)),
+            ("2001:db8::1", Some(SpecialRange::Reserved)),
+            ("192.0.0.8", Some(SpecialRange::Reserved)),
+            ("192.0.1.1", None),
+            ("198.18.0.1", Some(SpecialRange::Reserved)),
+            ("198.19.255.255", Some(SpecialRange::Reserved)),
+            ("198.20.0.1", None),
+            ("198.17.255.255", None),
+            ("64:ff9b::7.7.7.7", Some(SpecialRange::Reserved)),
+            ("64:ff9b::1:0:0", None),
+            ("64:ff9b:1::1", Some(SpecialRange::Private)),
+            ("64:ff9b:2::1", None),
+            ("100::1", Some(SpecialRange::Reserved)),
+            ("100:0:0:1::1", None),
+            ("2001:2::1", Some(SpecialRange::Reserved)),
+            ("2001:2:1::1", None),
+        ] {
+            assert_eq!(SpecialRange::of(ip.parse().unwrap()), expected, "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_geoip_special_ranges() -> Result<(), Box<dyn std::error::Error>> {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let metrics = Arc::new(Metrics::from_sink(
+            "test",
+            TestMetricSink { log: log.clone() },
+        ));
+        let geoip = super::GeoIp::builder()
+            .path("./GeoLite2-Country.mmdb")
+            .cache_size(10)
+            .cache_prefix_lens(8, 48)
+            .metrics(metrics)
+            .build()?;
+        let with_default = super::GeoIp::builder()
+            .path("./GeoLite2-Country.mmdb")
+            .private_country("US", "United States")
+            .build()?;
+
+        assert!(geoip.locate("10.0.0.1".parse()?)?.is_none());
+        assert!(geoip.locate("127.0.0.1".parse()?)?.is_none());
+        let rv = with_default.locate("10.0.0.1".parse()?)?.unwrap();
+        assert_eq!(rv.country_code.as_deref(), Some("US"));
+        assert_eq!(rv.country_name.as_deref(), Some("United States"));
+        assert!(with_default.locate("192.0.2.1".parse()?)?.is_none());
+
+        assert_eq!(
+            *log.lock().unwrap().deref(),
+            vec![
+                "test.location:1|c|#country:unknown,database:none,reason:private",
+                "test.location:1|c|#country:unknown,database:none,reason:reserved",
+            ],
+            "special ranges don't use the cache"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_geoip_without_databases() {
+        assert!(super::GeoIp::default()
+            .locate("7.7.7.7".parse().unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn test_geoip_overrides() -> Result<(), Box<dyn std::error::Error>> {
+        use std::fs;
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let metrics = Arc::new(Metrics::from_sink(
+            "test",
+            TestMetricSink { log: log.clone() },
+        ));
+        let dir = crate::reload::tests::TempDir::new();
+        let overrides_path = dir.join("overrides.json");
+        fs::write(
+            &overrides_path,
+            r#"[
+                {"network": "7.7.7.0/24", "country_code": "FR", "country_name": "France"},
+                {"network": "10.0.0.0/8", "country_code": "DE"}
+            ]"#,
+        )?;
+        let geoip = super::GeoIp::builder()
+            .overrides_path(&overrides_path)
+            .path("./GeoLite2-Country.mmdb")
+            .metrics(metrics)
+            .build()?;
+
+        let rv = geoip.locate("7.7.7.7".parse()?)?.unwrap();
+        assert_eq!(rv.country_code.as_deref(), Some("FR"));
+        assert_eq!(rv.country_name.as_deref(), Some("France"));
+        let rv = geoip.locate("7.7.8.8".parse()?)?.unwrap();
+        assert_eq!(rv.country_code.as_deref(), Some("US"));
+        let rv = geoip.locate("10.1.1.1".parse()?)?.unwrap();
+        assert_eq!(
+            rv.country_code.as_deref(),
+            Some("DE"),
+            "overrides win over private ranges"
+        );
+
+        assert_eq!(
+            *log.lock().unwrap().deref(),
+            vec![
+                "test.location:1|c|#country:FR,database:override,reason:public",
+                "test.location:1|c|#country:US,database:GeoLite2-Country,reason:public",
+                "test.location:1|c|#country:DE,database:override,reason:private",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_geoip_cache() -> Result<(), Box<dyn std::error::Error>> {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let metrics = Arc::new(Metrics::from_sink(
+            "test",
+            TestMetricSink { log: log.clone() },
+        ));
+        let geoip = super::GeoIp::builder()
+            .path("./GeoLite2-Country.mmdb")
+            .cache_size(10)
+            .cache_prefix_lens(24, 48)
+            .metrics(metrics)
+            .build()?;
+
+        let first = geoip.locate("7.7.7.7".parse()?)?;
+        let second = geoip.locate("7.7.7.8".parse()?)?;
+        assert_eq!(first, second);
+        assert_eq!(second.unwrap().country_code.as_deref(), Some("US"));
+        geoip.locate("8.8.8.8".parse()?)?;
+        geoip.locate("8.8.8.9".parse()?)?;
+
+        assert_eq!(
+            *log.lock().unwrap().deref(),
+            vec![
+                "test.geoip_cache_miss:1|c",
+                "test.location:1|c|#country:US,database:GeoLite2-Country,reason:public",
+                "test.geoip_cache_hit:1|c",
+                "test.location:1|c|#country:US,database:GeoLite2-Country,reason:public",
+                "test.geoip_cache_miss:1|c",
+                "test.location:1|c|#country:unknown,database:none,reason:public",
+                "test.geoip_cache_hit:1|c",
+                "test.location:1|c|#country:unknown,database:none,reason:public",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_geoip_cache_with_overrides() -> Result<(), Box<dyn std::error::Error>> {
+        use std::fs;
+
+        let dir = crate::reload::tests::TempDir::new();
+        let overrides_path = dir.join("overrides.json");
+        fs::write(
+            &overrides_path,
+            r#"[{"network": "7.7.7.7/32", "country_code": "FR"}]"#,
+        )?;
+        let geoip = super::GeoIp::builder()
+            .overrides_path(&overrides_path)
+            .path("./GeoLite2-Country.mmdb")
+            .cache_size(10)
+            .cache_prefix_lens(24, 48)
+            .build()?;
+
+        // Overrides don't spread to the rest of the cached network, whichever
+        // address is looked up first
+        for ip in ["7.7.7.7", "7.7.7.8", "7.7.7.7", "7.7.7.8"] {
+            let expected = if ip == "7.7.7.7" { "FR" } else { "US" };
+            let rv = geoip.locate(ip.parse()?)?.unwrap();
+            assert_eq!(rv.country_code.as_deref(), Some(expected), "{}", ip);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_geoip_cache_invalid_prefix() {
+        assert!(super::GeoIp::builder()
+            .cache_size(10)
+            .cache_prefix_lens(33, 128)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_geoip_city_database() -> Result<(), Box<dyn std::error::Error>> {
+        let geoip = super::GeoIp::builder()
+            .path("./GeoLite2-City.mmdb")
+            .build()?;
+
+        let rv = geoip.locate("7.7.7.7".parse()?)?.unwrap();
+        assert_eq!(rv.country_code.as_deref(), Some("US"));
+        assert_eq!(rv.continent_code.as_deref(), Some("NA"));
+        assert!(rv.time_zone.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_location_from_city_record() {
+        use maxminddb::geoip2::{self, city};
+
+        let names = |name| Some([("en", name)].into_iter().collect());
+        let record = geoip2::City {
+            city: Some(city::City {
+                geoname_id: None,
+                names: names("Toronto"),
+            }),
+            continent: Some(city::Continent {
+                code: Some("NA"),
+                geoname_id: None,
+                names: None,
+            }),
+            country: Some(city::Country {
+                geoname_id: None,
+                is_in_european_union: None,
+                iso_code: Some("CA"),
+                names: names("Canada"),
+            }),
+            location: Some(city::Location {
+                accuracy_radius: Some(20),
+                latitude: Some(43.6547),
+                longitude: Some(-79.3623),
+                metro_code: None,
+                time_zone: Some("America/Toronto"),
+            }),
+            postal: None,
+            registered_country: None,
+            represented_country: None,
+            subdivisions: Some(vec![city::Subdivision {
+                geoname_id: None,
+                iso_code: Some("ON"),
+                names: names("Ontario"),
+            }]),
+            traits: None,
+        };
+
+        assert_eq!(
+            super::Location::from(record),
+            super::Location {
+                country_code: Some("CA".to_owned()),
+                country_name: Some("Canada".to_owned()),
+                continent_code: Some("NA".to_owned()),
+                subdivision_code: Some("ON".to_owned()),
+                city_name: Some("Toronto".to_owned()),
+                time_zone: Some("America/Toronto".to_owned()),
+                coordinates: Some(super::Coordinates {
+                    latitude: 43.7,
+                    longitude: -79.4,
+                    accuracy_radius: Some(20),
+                }),
+            }
+        );
+    }
+
+    #[test]
+    fn test_geoip_metadata() -> Result<(), Box<dyn std::error::Error>> {
+        let geoip = super::GeoIp::builder()
+            .path("./GeoLite2-Country.mmdb")
+            .build()?;
+
+        let metadata = &geoip.metadata()[0];
+        assert_eq!(metadata.name, "GeoLite2-Country");
+        assert_eq!(metadata.database_type, "GeoLite2-Country");
+        assert_eq!(metadata.ip_version, 6);
+        assert!(metadata.languages.contains(&"en".to_owned()));
+        assert!(metadata.node_count > 0);
+
+        assert_eq!(super::GeoIp::default().metadata(), vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_note_stale() -> Result<(), Box<dyn std::error::Error>> {
+        let geoip = super::GeoIp::builder()
+            .path("./GeoLite2-Country.mmdb")
+            .build()?;
+        let mut metadata = geoip.metadata().remove(0);
+
+        assert!(geoip.note_stale(&metadata));
+        assert!(!geoip.note_stale(&metadata));
+
+        // A new build of the same database is reported again.
+        metadata.build_epoch += 1;
+        assert!(geoip.note_stale(&metadata));
+        assert!(!geoip.note_stale(&metadata));
+
+        metadata.name = "GeoLite2-City".to_owned();
+        assert!(!geoip.note_stale(&metadata));
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_age() {
+        let metadata = super::DatabaseMetadata {
+            name: "GeoLite2-Country".to_owned(),
+            database_type: "GeoLite2-Country".to_owned(),
+            build_epoch: 1_700_000_000,
+            ip_version: 6,
+            languages: vec!["en".to_owned()],
+            node_count: 1,
+        };
+        let build_time = metadata.build_time();
+
+        assert!(!metadata.is_older_than(7, build_time + chrono::Duration::days(7)));
+        assert!(metadata.is_older_than(
+            7,
+            build_time + chrono::Duration::days(7) + chrono::Duration::seconds(1)
+        ));
+    }
+
+    #[test]
+    fn test_failed_reload_keeps_previous_database() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::reload::{tests::TempDir, Reloadable};
+        use std::fs;
+
+        let dir = TempDir::new();
+        let path = dir.join("GeoLite2-Country.mmdb");
+        fs::copy("./GeoLite2-Country.mmdb", &path)?;
+        let geoip = super::GeoIp::builder().path(&path).build()?;
+
+        fs::write(&path, "not a database")?;
+        assert!(geoip.reload().is_err());
+        let rv = geoip.locate("7.7.7.7".parse()?)?.unwrap();
+        assert_eq!(rv.country_code.as_deref(), Some("US"));
+
+        fs::copy("./GeoLite2-Country.mmdb", &path)?;
+        assert!(geoip.reload().is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_partially_failed_reload() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::reload::{tests::TempDir, Reloadable};
+        use std::fs;
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let metrics = Arc::new(Metrics::from_sink(
+            "test",
+            TestMetricSink { log: log.clone() },
+        ));
+        let dir = TempDir::new();
+        let country_path = dir.join("GeoLite2-Country.mmdb");
+        let city_path = dir.join("GeoLite2-City.mmdb");
+        fs::copy("./GeoLite2-Country.mmdb", &country_path)?;
+        fs::copy("./GeoLite2-City.mmdb", &city_path)?;
+        let geoip = super::GeoIp::builder()
+            .paths([&country_path, &city_path])
+            .metrics(metrics)
+            .build()?;
+
+        fs::write(&city_path, "not a database")?;
+        assert!(
+            geoip.reload().is_ok(),
+            "reloading the other database counts as success"
+        );
+        assert_eq!(
+            *log.lock().unwrap().deref(),
+            vec!["test.geoip_reload_error:1|c|#file:GeoLite2-City"]
+        );
+        let rv = geoip.locate("7.7.7.7".parse()?)?.unwrap();
+        assert_eq!(rv.country_code.as_deref(), Some("US"));
+
+        Ok(())
+    }
 }
@@ -1,48 +1,369 @@
-use serde_json::{from_str, Value};
+use crate::{errors::ClassifyError, ratelimit::RateLimit, reload::Reloadable};
+use chrono::NaiveDate;
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{from_str, from_value, Value};
 use slog::Logger;
-use std::collections::HashSet;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fs::read_to_string;
-use std::path::PathBuf;
-
-pub fn load(file_path: PathBuf, app_log: Logger) -> HashSet<String> {
-    let mut keys: HashSet<String> = HashSet::new();
-
-    match read_to_string(file_path) {
-        Ok(contents) => match from_str::<Value>(&contents) {
-            Ok(json_value) => {
-                if let Some(array) = json_value.as_array() {
-                    for item in array {
-                        if let Value::String(string) = &item {
-                            keys.insert(string.to_string());
-                        }
-                    }
-                }
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// Something extra that an API key is allowed to do.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Capability {
+    /// Look up the location of an arbitrary IP, rather than the client's own.
+    Lookup,
+}
+
+/// An endpoint that requires an API key.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Endpoint {
+    /// `/v1/country`
+    Country,
+    /// `/v1/country/batch`
+    CountryBatch,
+}
+
+impl Endpoint {
+    /// The name of the endpoint, as used in the API keys file and in metrics.
+    pub fn name(self) -> &'static str {
+        match self {
+            Endpoint::Country => "country",
+            Endpoint::CountryBatch => "country_batch",
+        }
+    }
+}
+
+/// Why a known API key was not accepted.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeyRejection {
+    Disabled,
+    Expired,
+    EndpointNotAllowed,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// An API key, as listed in the API keys file.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ApiKey {
+    pub key: String,
+    /// Who the key was issued to, like a project or team name.
+    pub owner: Option<String>,
+    /// How to reach the owner, like an email address.
+    pub contact: Option<String>,
+    pub description: Option<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub created: Option<NaiveDate>,
+    /// The last day, in UTC, that the key may be used.
+    pub expires: Option<NaiveDate>,
+    /// The endpoints that the key may be used with. All of them if unset.
+    pub endpoints: Option<Vec<Endpoint>>,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    /// How often the key may be used with `/v1/country` and
+    /// `/v1/country/batch`. Unlimited if unset.
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl ApiKey {
+    pub fn new<S: Into<String>>(key: S) -> Self {
+        Self {
+            key: key.into(),
+            owner: None,
+            contact: None,
+            description: None,
+            enabled: true,
+            created: None,
+            expires: None,
+            endpoints: None,
+            capabilities: Vec::new(),
+            rate_limit: None,
+        }
+    }
+
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// Check whether the key may be used with `endpoint` on the day `today`.
+    pub fn check(&self, endpoint: Endpoint, today: NaiveDate) -> Result<(), KeyRejection> {
+        if !self.enabled {
+            return Err(KeyRejection::Disabled);
+        }
+        if self.expires.is_some_and(|expires| today > expires) {
+            return Err(KeyRejection::Expired);
+        }
+        if let Some(endpoints) = &self.endpoints {
+            if !endpoints.contains(&endpoint) {
+                return Err(KeyRejection::EndpointNotAllowed);
             }
-            Err(err) => {
-                slog::error!(app_log, "Error parsing api keys file. {}", err)
+        }
+        Ok(())
+    }
+}
+
+/// A named pattern for keys that downstream projects can pick for themselves,
+/// without being listed in the API keys file.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct DownstreamKeyPattern {
+    name: String,
+    pattern: String,
+}
+
+/// The patterns that self-selected downstream keys may match, in order. They
+/// are configured as a JSON array like
+///
+/// ```json
+/// [{"name": "firefox-downstream", "pattern": "^firefox-downstream-\\w{1,40}$"}]
+/// ```
+///
+/// Requests with a matching key are counted under the name of the pattern,
+/// rather than the key itself, so that metrics have a bounded set of tags.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct DownstreamKeys {
+    patterns: Vec<(DownstreamKeyPattern, Regex)>,
+}
+
+impl DownstreamKeys {
+    /// Compile `(name, pattern)` pairs.
+    pub fn new<I, S>(patterns: I) -> Result<Self, ClassifyError>
+    where
+        I: IntoIterator<Item = (S, S)>,
+        S: Into<String>,
+    {
+        let patterns = patterns
+            .into_iter()
+            .map(|(name, pattern)| {
+                let pattern = DownstreamKeyPattern {
+                    name: name.into(),
+                    pattern: pattern.into(),
+                };
+                let regex = Regex::new(&pattern.pattern).map_err(|err| {
+                    ClassifyError::from_source(
+                        format!("Invalid downstream key pattern {}", pattern.name),
+                        err,
+                    )
+                })?;
+                Ok((pattern, regex))
+            })
+            .collect::<Result<_, ClassifyError>>()?;
+        Ok(Self { patterns })
+    }
+
+    /// The name of the first pattern that `key` matches.
+    pub fn matching(&self, key: &str) -> Option<&str> {
+        self.patterns
+            .iter()
+            .find(|(_, regex)| regex.is_match(key))
+            .map(|(pattern, _)| pattern.name.as_str())
+    }
+}
+
+impl Default for DownstreamKeys {
+    fn default() -> Self {
+        Self::new([("firefox-downstream", r"^firefox-downstream-\w{1,40}$")])
+            .expect("default downstream key pattern is valid")
+    }
+}
+
+impl TryFrom<String> for DownstreamKeys {
+    type Error = ClassifyError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let patterns: Vec<DownstreamKeyPattern> = from_str(&value)?;
+        Self::new(
+            patterns
+                .into_iter()
+                .map(|pattern| (pattern.name, pattern.pattern)),
+        )
+    }
+}
+
+impl From<DownstreamKeys> for String {
+    fn from(value: DownstreamKeys) -> Self {
+        let patterns: Vec<DownstreamKeyPattern> = value
+            .patterns
+            .into_iter()
+            .map(|(pattern, _)| pattern)
+            .collect();
+        serde_json::to_string(&patterns).expect("patterns can be serialized")
+    }
+}
+
+/// Load API keys from a JSON array. Each item is either a key as a string, or
+/// an object describing the key, like
+///
+/// ```json
+/// {
+///   "key": "...",
+///   "owner": "Example Browser",
+///   "contact": "dev@example.com",
+///   "description": "Default search region",
+///   "enabled": true,
+///   "created": "2024-01-31",
+///   "expires": "2025-01-31",
+///   "endpoints": ["country", "country_batch"],
+///   "capabilities": ["lookup"],
+///   "rate_limit": {"requests_per_second": 10, "burst": 20}
+/// }
+/// ```
+///
+/// Only `key` is required. Keys that can't be parsed are logged and skipped.
+pub fn load(file_path: PathBuf, app_log: Logger) -> HashMap<String, ApiKey> {
+    match read(&file_path) {
+        Ok((keys, errors)) => {
+            for err in errors {
+                slog::error!(app_log, "{}", err);
             }
-        },
+            keys
+        }
         Err(err) => {
-            slog::error!(app_log, "Error reading api keys file. {}", err)
+            slog::error!(app_log, "{}", err);
+            HashMap::new()
+        }
+    }
+}
+
+/// Read API keys from `file_path`, along with an error for each key that
+/// can't be parsed. A file that can't be read or parsed as a whole is an
+/// error.
+fn read(file_path: &Path) -> Result<(HashMap<String, ApiKey>, Vec<String>), String> {
+    let mut keys: HashMap<String, ApiKey> = HashMap::new();
+    let mut errors = Vec::new();
+
+    let contents =
+        read_to_string(file_path).map_err(|err| format!("Error reading api keys file. {}", err))?;
+    let json_value = from_str::<Value>(&contents)
+        .map_err(|err| format!("Error parsing api keys file. {}", err))?;
+    let array = json_value
+        .as_array()
+        .ok_or_else(|| "Error parsing api keys file. Expected an array".to_owned())?;
+
+    for item in array {
+        match item {
+            Value::String(string) => {
+                keys.insert(string.to_string(), ApiKey::new(string));
+            }
+            Value::Object(_) => match from_value::<ApiKey>(item.clone()) {
+                Ok(api_key) => {
+                    keys.insert(api_key.key.clone(), api_key);
+                }
+                Err(err) => errors.push(format!("Error parsing api key. {}", err)),
+            },
+            _ => {}
+        }
+    }
+
+    Ok((keys, errors))
+}
+
+/// The set of API keys in use, which can be reloaded from the API keys file
+/// while the server is running.
+#[derive(Debug)]
+pub struct ApiKeys {
+    path: Option<PathBuf>,
+    keys: RwLock<Arc<HashMap<String, ApiKey>>>,
+}
+
+impl ApiKeys {
+    /// Load keys from `file_path`. If the file can't be loaded, start with no
+    /// keys, so that a later reload can fix things.
+    pub fn open(file_path: PathBuf, app_log: Logger) -> Self {
+        Self {
+            keys: RwLock::new(Arc::new(load(file_path.clone(), app_log))),
+            path: Some(file_path),
+        }
+    }
+
+    /// A fixed set of keys, not backed by a file.
+    pub fn new<I: IntoIterator<Item = ApiKey>>(keys: I) -> Self {
+        let keys = keys
+            .into_iter()
+            .map(|api_key| (api_key.key.clone(), api_key))
+            .collect();
+        Self {
+            path: None,
+            keys: RwLock::new(Arc::new(keys)),
         }
     }
 
-    keys
+    pub fn get(&self, key: &str) -> Option<ApiKey> {
+        self.keys
+            .read()
+            .expect("api keys lock poisoned")
+            .get(key)
+            .cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.read().expect("api keys lock poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for ApiKeys {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl Reloadable for ApiKeys {
+    fn name(&self) -> &'static str {
+        "api_keys"
+    }
+
+    fn watched_paths(&self) -> Vec<PathBuf> {
+        self.path.iter().cloned().collect()
+    }
+
+    /// Read the API keys file again. If it can't be read or parsed, or any
+    /// key in it can't be parsed, the previous keys stay in place.
+    fn reload(&self) -> Result<(), ClassifyError> {
+        let path = self
+            .path
+            .as_ref()
+            .ok_or_else(|| ClassifyError::new("No api keys file configured"))?;
+        let (keys, errors) = read(path).map_err(ClassifyError::new)?;
+        if !errors.is_empty() {
+            return Err(ClassifyError::new(errors.join(" ")));
+        }
+        *self.keys.write().expect("api keys lock poisoned") = Arc::new(keys);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
-mod tests {
-    use crate::keys::load;
+pub mod tests {
+    use crate::{
+        keys::{load, ApiKey, ApiKeys, Capability, DownstreamKeys, Endpoint, KeyRejection},
+        ratelimit::RateLimit,
+        reload::{tests::TempDir, Reloadable},
+    };
+    use chrono::NaiveDate;
     use slog::Drain;
     use slog::{OwnedKVList, Record};
     use std::{
+        convert::TryFrom,
         fs,
         path::PathBuf,
         sync::{Arc, Mutex},
     };
 
-    struct VecDrain {
-        logs: Arc<Mutex<Vec<String>>>,
+    /// Collects the level and message of each log record.
+    pub struct VecDrain {
+        pub logs: Arc<Mutex<Vec<String>>>,
     }
 
     impl Drain for VecDrain {
@@ -99,4 +420,169 @@ mod tests {
         let _ = fs::remove_file(corrupt_file);
         let _ = fs::remove_file(good_file);
     }
+
+    #[test]
+    fn test_load_key_objects() {
+        let logs = Arc::new(Mutex::new(Vec::new()));
+        let logger =
+            slog::Logger::root(slog::Fuse::new(VecDrain { logs: logs.clone() }), slog::o!());
+
+        let dir = TempDir::new();
+        let file = dir.join("apiKeys.json");
+        let _ = fs::write(
+            file.clone(),
+            r#"["plain", {"key": "admin", "capabilities": ["lookup"]}, {"key": "bad", "capabilities": ["root"]}]"#,
+        );
+
+        let keys = load(file.clone(), logger);
+        assert_eq!(keys.len(), 2);
+        assert!(!keys["plain"].has_capability(Capability::Lookup));
+        assert!(keys["admin"].has_capability(Capability::Lookup));
+        assert!(logs
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap()
+            .starts_with("ERRO / Error parsing api key."));
+    }
+
+    #[test]
+    fn test_load_key_metadata() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        let dir = TempDir::new();
+        let file = dir.join("apiKeys.json");
+        let _ = fs::write(
+            file.clone(),
+            r#"[{
+                "key": "partner",
+                "owner": "Example Browser",
+                "contact": "dev@example.com",
+                "description": "Default search region",
+                "enabled": false,
+                "created": "2024-01-31",
+                "expires": "2025-01-31",
+                "endpoints": ["country"],
+                "rate_limit": {"requests_per_second": 0.5, "burst": 5}
+            }]"#,
+        );
+
+        let keys = load(file.clone(), logger);
+        let key = &keys["partner"];
+        assert_eq!(key.owner.as_deref(), Some("Example Browser"));
+        assert_eq!(key.contact.as_deref(), Some("dev@example.com"));
+        assert_eq!(key.description.as_deref(), Some("Default search region"));
+        assert!(!key.enabled);
+        assert_eq!(key.created, NaiveDate::from_ymd_opt(2024, 1, 31));
+        assert_eq!(key.expires, NaiveDate::from_ymd_opt(2025, 1, 31));
+        assert_eq!(key.endpoints, Some(vec![Endpoint::Country]));
+        assert_eq!(key.rate_limit, Some(RateLimit::new(0.5, 5)));
+    }
+
+    #[test]
+    fn test_downstream_keys() {
+        let default = DownstreamKeys::default();
+        assert_eq!(
+            default.matching("firefox-downstream-foo_bar"),
+            Some("firefox-downstream")
+        );
+        assert_eq!(default.matching("firefox-downstream-foo-bar"), None);
+        assert_eq!(default.matching("thunderbird-foo"), None);
+
+        let keys = DownstreamKeys::try_from(
+            r#"[
+                {"name": "thunderbird", "pattern": "^thunderbird-\\w+$"},
+                {"name": "fenix", "pattern": "^fenix-"}
+            ]"#
+            .to_owned(),
+        )
+        .unwrap();
+        assert_eq!(keys.matching("thunderbird-daily"), Some("thunderbird"));
+        assert_eq!(keys.matching("fenix-anything-goes"), Some("fenix"));
+        assert_eq!(keys.matching("firefox-downstream-foo"), None);
+
+        // Round trips through its setting format
+        let keys = DownstreamKeys::try_from(String::from(keys)).unwrap();
+        assert_eq!(keys.matching("fenix-x"), Some("fenix"));
+
+        assert!(
+            DownstreamKeys::try_from(r#"[{"name": "bad", "pattern": "("}]"#.to_owned()).is_err()
+        );
+        assert!(DownstreamKeys::try_from("^firefox-".to_owned()).is_err());
+    }
+
+    #[test]
+    fn test_check_key() {
+        let today = NaiveDate::from_ymd_opt(2025, 1, 31).unwrap();
+
+        let key = ApiKey::new("plain");
+        assert_eq!(key.check(Endpoint::Country, today), Ok(()));
+        assert_eq!(key.check(Endpoint::CountryBatch, today), Ok(()));
+
+        let disabled = ApiKey {
+            enabled: false,
+            ..ApiKey::new("disabled")
+        };
+        assert_eq!(
+            disabled.check(Endpoint::Country, today),
+            Err(KeyRejection::Disabled)
+        );
+
+        let expiring = ApiKey {
+            expires: Some(today),
+            ..ApiKey::new("expiring")
+        };
+        assert_eq!(expiring.check(Endpoint::Country, today), Ok(()));
+        assert_eq!(
+            expiring.check(Endpoint::Country, today.succ_opt().unwrap()),
+            Err(KeyRejection::Expired)
+        );
+
+        let restricted = ApiKey {
+            endpoints: Some(vec![Endpoint::Country]),
+            ..ApiKey::new("restricted")
+        };
+        assert_eq!(restricted.check(Endpoint::Country, today), Ok(()));
+        assert_eq!(
+            restricted.check(Endpoint::CountryBatch, today),
+            Err(KeyRejection::EndpointNotAllowed)
+        );
+    }
+
+    #[test]
+    fn test_reload() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let dir = TempDir::new();
+        let file = dir.join("apiKeys.json");
+        let _ = fs::write(file.clone(), "[\"foo\"]");
+
+        let keys = ApiKeys::open(file.clone(), logger);
+        assert!(keys.get("foo").is_some());
+
+        let _ = fs::write(file.clone(), "[\"foo\", \"bar\"]");
+        assert!(keys.reload().is_ok());
+        assert_eq!(keys.len(), 2);
+        assert!(keys.get("bar").is_some());
+
+        // A broken file keeps the previous keys
+        let _ = fs::write(file.clone(), "[\"baz\"]z");
+        assert!(keys.reload().is_err());
+        assert_eq!(keys.len(), 2);
+        assert!(keys.get("baz").is_none());
+
+        // So does a file with a key that can't be parsed
+        let _ = fs::write(
+            file.clone(),
+            r#"["foo", "bar", "baz", {"key": "partner", "expires": "2025-02-30"}]"#,
+        );
+        assert!(keys.reload().is_err());
+        assert_eq!(keys.len(), 2);
+        assert!(keys.get("baz").is_none());
+
+        let _ = fs::remove_file(file.clone());
+        assert!(keys.reload().is_err());
+        assert_eq!(keys.len(), 2);
+
+        assert!(ApiKeys::default().reload().is_err());
+    }
 }
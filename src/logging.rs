@@ -4,6 +4,8 @@ use actix_web::{
     Error, HttpRequest, HttpResponse,
 };
 use futures::{future, Future, FutureExt};
+use percent_encoding::percent_decode_str;
+use sentry::protocol::Event;
 use slog::{self, Drain};
 use slog_derive::KV;
 use slog_mozlog_json::MozLogJson;
@@ -67,12 +69,62 @@ impl MozLogFields {
         self
     }
 
+    /// Replace the value of the `key` query parameter in `path`, so that API
+    /// keys don't end up in logs.
+    fn redact_api_key(mut self) -> Self {
+        if let Some(path) = &self.path {
+            if let Some((route, query)) = path.split_once('?') {
+                self.path = Some(format!("{}?{}", route, redact_api_key_query(query)));
+            }
+        }
+        self
+    }
+
     fn add_response<B>(mut self, response: &HttpResponse<B>) -> Self {
         self.code = Some(response.status().as_u16());
         self
     }
 }
 
+/// Replace the value of the `key` parameter in a query string. Parameter
+/// names are percent-decoded first, the same way the endpoints read them.
+fn redact_api_key_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| {
+            let name = pair.split_once('=').map_or(pair, |(name, _)| name);
+            if percent_decode_str(name).decode_utf8_lossy() == "key" {
+                "key=REDACTED"
+            } else {
+                pair
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Remove API keys from the request details of a Sentry event, which would
+/// otherwise include the full URL and headers. Meant for `before_send`.
+pub fn scrub_sentry_event(mut event: Event<'static>) -> Option<Event<'static>> {
+    if let Some(request) = &mut event.request {
+        if let Some(url) = &mut request.url {
+            if let Some(query) = url.query().map(redact_api_key_query) {
+                url.set_query(Some(&query));
+            }
+        }
+        if let Some(query) = &request.query_string {
+            request.query_string = Some(redact_api_key_query(query));
+        }
+        for (name, value) in request.headers.iter_mut() {
+            if name.eq_ignore_ascii_case("authorization") || name.eq_ignore_ascii_case("x-api-key")
+            {
+                *value = "REDACTED".to_owned();
+            }
+        }
+    }
+    Some(event)
+}
+
 pub struct RequestLogger;
 
 impl<S, B> Transform<S, ServiceRequest> for RequestLogger
@@ -110,14 +162,17 @@ where
     actix_web::dev::forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let log = match req.app_data::<Data<EndpointState>>() {
-            Some(state) => state.log.clone(),
+        let (log, redact_api_keys) = match req.app_data::<Data<EndpointState>>() {
+            Some(state) => (state.log.clone(), state.log_redact_api_keys),
             None => return Box::pin(self.service.call(req)),
         };
 
         Box::pin(self.service.call(req).then(move |res| match res {
             Ok(val) => {
-                let fields = MozLogFields::new(&val);
+                let mut fields = MozLogFields::new(&val);
+                if redact_api_keys {
+                    fields = fields.redact_api_key();
+                }
                 slog::info!(log, "" ; slog::o!(fields));
                 future::ok(val)
             }
@@ -129,8 +184,9 @@ where
 
 #[cfg(test)]
 mod tests {
-    use crate::logging::MozLogFields;
+    use crate::logging::{scrub_sentry_event, MozLogFields};
     use actix_web::{http, test, HttpResponse};
+    use sentry::protocol::{Event, Request};
 
     #[test]
     async fn test_request_fields() {
@@ -149,4 +205,64 @@ mod tests {
         assert_eq!(fields.lang, None);
         assert_eq!(fields.remote, None);
     }
+
+    #[test]
+    async fn test_redact_api_key() {
+        let request = test::TestRequest::get()
+            .uri("/v1/country?key=secret&ip=1.2.3.4&monkey=1")
+            .to_http_request();
+        let fields = MozLogFields::default().add_request(&request);
+        assert_eq!(
+            fields.path.as_deref(),
+            Some("/v1/country?key=secret&ip=1.2.3.4&monkey=1")
+        );
+        assert_eq!(
+            fields.redact_api_key().path.as_deref(),
+            Some("/v1/country?key=REDACTED&ip=1.2.3.4&monkey=1")
+        );
+
+        // Encoded parameter names are redacted too
+        let request = test::TestRequest::get()
+            .uri("/v1/country?k%65y=secret&%6B%65%79")
+            .to_http_request();
+        let fields = MozLogFields::default().add_request(&request);
+        assert_eq!(
+            fields.redact_api_key().path.as_deref(),
+            Some("/v1/country?key=REDACTED&key=REDACTED")
+        );
+
+        let request = test::TestRequest::get().uri("/").to_http_request();
+        let fields = MozLogFields::default().add_request(&request);
+        assert_eq!(fields.redact_api_key().path.as_deref(), Some("/"));
+    }
+
+    #[test]
+    async fn test_scrub_sentry_event() {
+        let event = Event {
+            request: Some(Request {
+                url: "https://example.com/v1/country?ip=1.2.3.4&key=secret"
+                    .parse()
+                    .ok(),
+                headers: [
+                    ("authorization", "Bearer secret"),
+                    ("x-api-key", "secret"),
+                    ("user-agent", "test"),
+                ]
+                .into_iter()
+                .map(|(name, value)| (name.to_owned(), value.to_owned()))
+                .collect(),
+                ..Request::default()
+            }),
+            ..Event::default()
+        };
+
+        let request = scrub_sentry_event(event).unwrap().request.unwrap();
+        assert_eq!(
+            request.url.unwrap().as_str(),
+            "https://example.com/v1/country?ip=1.2.3.4&key=REDACTED"
+        );
+        assert_eq!(request.headers["authorization"], "REDACTED");
+        assert_eq!(request.headers["x-api-key"], "REDACTED");
+        assert_eq!(request.headers["user-agent"], "test");
+    }
 }
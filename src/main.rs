@@ -8,46 +8,105 @@ pub mod geoip;
 pub mod keys;
 pub mod logging;
 pub mod metrics;
+pub mod overrides;
+pub mod prometheus;
+pub mod ratelimit;
+pub mod reload;
 pub mod settings;
+pub mod shutdown;
+pub mod telemetry;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub mod utils;
 
 use crate::{
     endpoints::{canned, classify, country, debug, dockerflow, EndpointState},
     errors::ClassifyError,
     geoip::GeoIp,
+    keys::ApiKeys,
+    metrics::TagValues,
+    ratelimit::{ClientRateLimit, ClientRateLimits, RateLimit, RateLimiter},
     settings::Settings,
 };
 use actix_web::{
     web::{self, Data},
     App,
 };
-use std::sync::Arc;
+use opentelemetry::trace::TracerProvider;
+use std::{
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
 
 const APP_NAME: &str = "classify-client";
 
 #[actix_web::main]
 async fn main() -> Result<(), ClassifyError> {
     let Settings {
+        admin_port,
         api_keys_file,
+        api_keys_reload_interval,
+        backlog,
+        client_rate_limit_exempt,
+        client_rate_limits,
+        country_batch_max_size,
         debug,
-        geoip_db_path,
+        downstream_key_burst,
+        downstream_key_patterns,
+        downstream_key_rate_limit,
+        extended_classification,
+        forwarded_header,
+        geoip_cache_ipv4_prefix_len,
+        geoip_cache_ipv6_prefix_len,
+        geoip_cache_size,
+        geoip_db_paths,
+        geoip_max_age_days,
+        geoip_overrides_path,
+        geoip_private_country_code,
+        geoip_private_country_name,
+        geoip_reload_interval,
+        geoip_stale_policy,
         host,
         human_logs,
+        invalid_hop_policy,
+        keep_alive,
+        log_redact_api_keys,
+        metrics_exporter,
+        metrics_max_tag_values,
         metrics_target,
+        otlp_endpoint,
         port,
         sentry_dsn,
         sentry_env,
         sentry_sample_rate,
+        shutdown_grace_period,
+        shutdown_timeout,
+        tls_cert_path,
+        tls_handshake_timeout,
+        tls_key_path,
+        tls_port,
+        tls_reload_interval,
+        tracing_sample_rate,
+        trusted_client_ip_header,
+        trusted_client_ip_header_peers,
+        trusted_proxy_hops,
         trusted_proxy_list,
         version_file,
+        workers,
         ..
     } = Settings::load()?;
 
     let app_log = logging::get_logger("app", human_logs);
 
+    let registry = Arc::new(prometheus::Registry::default());
     let metrics = Arc::new(
-        metrics::get_client(metrics_target, app_log.clone())
-            .unwrap_or_else(|err| panic!("Critical failure setting up metrics logging: {}", err)),
+        metrics::get_client(
+            metrics_target,
+            metrics_exporter,
+            Arc::clone(&registry),
+            app_log.clone(),
+        )
+        .unwrap_or_else(|err| panic!("Critical failure setting up metrics logging: {}", err)),
     );
 
     let _guard = sentry::init((
@@ -56,46 +115,150 @@ async fn main() -> Result<(), ClassifyError> {
             release: sentry::release_name!(),
             environment: Some(sentry_env.into()),
             sample_rate: sentry_sample_rate,
+            before_send: Some(Arc::new(logging::scrub_sentry_event)),
             ..Default::default()
         },
     ));
 
+    let mut geoip_builder = GeoIp::builder()
+        .paths(geoip_db_paths)
+        .cache_size(geoip_cache_size)
+        .cache_prefix_lens(geoip_cache_ipv4_prefix_len, geoip_cache_ipv6_prefix_len)
+        .metrics(Arc::clone(&metrics))
+        .log(app_log.clone())
+        .max_tag_values(metrics_max_tag_values);
+    if let Some(path) = geoip_overrides_path {
+        geoip_builder = geoip_builder.overrides_path(path);
+    }
+    match (geoip_private_country_code, geoip_private_country_name) {
+        (Some(country_code), Some(country_name)) => {
+            geoip_builder = geoip_builder.private_country(country_code, country_name);
+        }
+        (None, None) => {}
+        _ => {
+            return Err(ClassifyError::new(
+                "GEOIP_PRIVATE_COUNTRY_CODE and GEOIP_PRIVATE_COUNTRY_NAME must be set together",
+            ))
+        }
+    }
+    let geoip = Arc::new(geoip_builder.build()?);
+    reload::spawn_watcher(
+        geoip.clone(),
+        Duration::from_secs(geoip_reload_interval),
+        app_log.clone(),
+        Arc::clone(&metrics),
+    );
+
+    let api_keys = Arc::new(ApiKeys::open(api_keys_file, app_log.clone()));
+    reload::spawn_watcher(
+        api_keys.clone(),
+        Duration::from_secs(api_keys_reload_interval),
+        app_log.clone(),
+        Arc::clone(&metrics),
+    );
+
+    #[cfg(feature = "tls")]
+    let tls_certificate = match (tls_cert_path, tls_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let certificate = Arc::new(tls::TlsCertificate::open(cert_path, key_path)?);
+            reload::spawn_watcher(
+                certificate.clone(),
+                Duration::from_secs(tls_reload_interval),
+                app_log.clone(),
+                Arc::clone(&metrics),
+            );
+            Some(certificate)
+        }
+        (None, None) => None,
+        _ => {
+            return Err(ClassifyError::new(
+                "TLS_CERT_PATH and TLS_KEY_PATH must be set together",
+            ))
+        }
+    };
+    #[cfg(not(feature = "tls"))]
+    {
+        if tls_cert_path.is_some() || tls_key_path.is_some() {
+            return Err(ClassifyError::new(
+                "TLS_CERT_PATH and TLS_KEY_PATH require building with the \"tls\" feature",
+            ));
+        }
+        let _ = (tls_port, tls_reload_interval, tls_handshake_timeout);
+    }
+
+    let tracer_provider = match otlp_endpoint {
+        Some(endpoint) => {
+            slog::info!(app_log, "sending traces to {}", endpoint);
+            Some(telemetry::provider(&endpoint, tracing_sample_rate)?)
+        }
+        None => None,
+    };
+    let tracer = tracer_provider
+        .as_ref()
+        .map(|provider| provider.tracer(APP_NAME));
+
+    let draining = Arc::new(AtomicBool::new(false));
     let state = EndpointState {
-        api_keys_hashset: keys::load(api_keys_file, app_log.clone()),
-        geoip: Arc::new(
-            GeoIp::builder()
-                .path(geoip_db_path)
-                .metrics(Arc::clone(&metrics))
-                .build()?,
-        ),
+        api_keys,
+        api_key_limiter: Arc::new(RateLimiter::default()),
+        api_key_tags: Arc::new(TagValues::new(metrics_max_tag_values)),
+        client_rate_limits: Arc::new(ClientRateLimits::new(
+            client_rate_limits,
+            client_rate_limit_exempt,
+        )),
+        country_batch_max_size,
+        draining: Arc::clone(&draining),
+        downstream_key_limiter: Arc::new(RateLimiter::default()),
+        downstream_key_rate_limit: downstream_key_rate_limit
+            .map(|rate| RateLimit::new(rate, downstream_key_burst)),
+        downstream_keys: downstream_key_patterns,
+        extended_classification,
+        forwarded_header,
+        geoip,
+        geoip_max_age_days,
+        geoip_stale_policy,
+        invalid_hop_policy,
         metrics,
+        tracer,
         trusted_proxies: trusted_proxy_list,
+        trusted_proxy_hops,
+        trusted_client_ip_header,
+        trusted_client_ip_header_peers,
         log: app_log.clone(),
+        log_redact_api_keys,
         version_file,
     };
 
-    let addr = format!("{}:{}", host, port);
-    slog::info!(app_log, "starting server on https://{}", addr);
-
-    actix_web::HttpServer::new(move || {
+    let app = move || {
         let mut app = App::new()
             .app_data(Data::new(state.clone()))
+            .wrap(ClientRateLimit)
             .wrap(metrics::ResponseTimer)
             .wrap(logging::RequestLogger)
             .wrap(sentry_actix::Sentry::new())
+            .wrap(telemetry::RequestTracing)
             // API Endpoints
             .service(web::resource("/").route(web::get().to(classify::classify_client)))
             .service(
                 web::resource("/api/v1/classify_client/")
                     .route(web::get().to(classify::classify_client)),
             )
+            .service(
+                web::resource("/api/v2/classify_client/")
+                    .route(web::get().to(classify::classify_client_v2)),
+            )
             .service(web::resource("/v1/country").route(web::route().to(country::get_country)))
+            .service(
+                web::resource("/v1/country/batch")
+                    .route(web::post().to(country::get_country_batch)),
+            )
             // Dockerflow Endpoints
             .service(
                 web::resource("/__lbheartbeat__").route(web::get().to(dockerflow::lbheartbeat)),
             )
             .service(web::resource("/__heartbeat__").route(web::get().to(dockerflow::heartbeat)))
             .service(web::resource("/__version__").route(web::get().to(dockerflow::version)))
+            .service(web::resource("/__geoip__").route(web::get().to(dockerflow::geoip)))
             // Static responses
             // no /v1/geolocate, intentionally returning 404
             .service(web::resource("/v1/geosubmit").route(web::to(canned::forbidden)))
@@ -107,10 +270,64 @@ async fn main() -> Result<(), ClassifyError> {
         }
 
         app
-    })
-    .bind(&addr)?
-    .run()
-    .await?;
+    };
+
+    let addr = format!("{}:{}", host, port);
+    slog::info!(app_log, "starting server on http://{}", addr);
+    let mut server = actix_web::HttpServer::new(app)
+        // Shutdown is handled by `shutdown::spawn_handler`.
+        .disable_signals()
+        .shutdown_timeout(shutdown_timeout)
+        .keep_alive(Duration::from_secs(keep_alive))
+        .backlog(backlog);
+    if let Some(workers) = workers {
+        server = server.workers(workers);
+    }
+    let server = server.bind(&addr)?;
+
+    #[cfg(feature = "tls")]
+    let server = match tls_certificate {
+        Some(certificate) => {
+            let tls_addr = format!("{}:{}", host, tls_port);
+            slog::info!(app_log, "starting server on https://{}", tls_addr);
+            server
+                .tls_handshake_timeout(Duration::from_secs(tls_handshake_timeout))
+                .bind_rustls_0_23(&tls_addr, certificate.server_config()?)?
+        }
+        None => server,
+    };
+
+    let mut servers = vec![server.run()];
+
+    if metrics_exporter.prometheus() {
+        let admin_addr = format!("{}:{}", host, admin_port);
+        slog::info!(app_log, "serving metrics on http://{}/metrics", admin_addr);
+        let admin_server = actix_web::HttpServer::new(move || {
+            App::new()
+                .app_data(Data::new(Arc::clone(&registry)))
+                .service(web::resource("/metrics").route(web::get().to(prometheus::metrics)))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(&admin_addr)?
+        .run();
+        servers.push(admin_server);
+    }
+
+    shutdown::spawn_handler(
+        servers.iter().map(|server| server.handle()).collect(),
+        draining,
+        Duration::from_secs(shutdown_grace_period),
+        app_log.clone(),
+    );
+    futures::future::try_join_all(servers).await?;
+
+    // Send any spans that are still waiting to be exported.
+    if let Some(provider) = tracer_provider {
+        if let Err(err) = provider.shutdown() {
+            slog::error!(app_log, "Could not flush traces: {}", err);
+        }
+    }
 
     Ok(())
 }
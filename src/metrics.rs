@@ -1,23 +1,201 @@
-use crate::{endpoints::EndpointState, errors::ClassifyError, APP_NAME};
+use crate::{
+    endpoints::EndpointState, errors::ClassifyError, prometheus::Registry,
+    settings::MetricsExporter, APP_NAME,
+};
 use actix_web::{
     dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::StatusCode,
     web::Data,
     Error,
 };
-use cadence::{prelude::*, BufferedUdpMetricSink, StatsdClient};
+use cadence::{prelude::*, BufferedUdpMetricSink, MetricSink, StatsdClient};
 use futures::{future, Future, FutureExt};
 use std::{
+    collections::HashSet,
     fmt::Display,
     net::{ToSocketAddrs, UdpSocket},
+    panic::RefUnwindSafe,
     pin::Pin,
-    time::Instant,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
-pub fn get_client<A>(metrics_target: A, log: slog::Logger) -> Result<StatsdClient, ClassifyError>
+/// The tag value reported in place of values beyond the limit of a `TagValues`.
+pub const OTHER_TAG_VALUE: &str = "other";
+
+/// Limits the number of distinct values that a metrics tag can take, so that
+/// tags based on user input can't create an unbounded number of metric series.
+/// The first `max` distinct values are reported as they are, and any values
+/// after that are reported as `OTHER_TAG_VALUE`.
+#[derive(Debug)]
+pub struct TagValues {
+    max: usize,
+    seen: Mutex<HashSet<String>>,
+}
+
+impl TagValues {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            seen: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// The value to use in a tag for `value`.
+    pub fn get<'a>(&self, value: &'a str) -> &'a str {
+        let mut seen = self.seen.lock().expect("tag values lock poisoned");
+        if seen.contains(value) {
+            value
+        } else if seen.len() < self.max {
+            seen.insert(value.to_owned());
+            value
+        } else {
+            OTHER_TAG_VALUE
+        }
+    }
+}
+
+impl Default for TagValues {
+    fn default() -> Self {
+        Self::new(300)
+    }
+}
+
+/// A value reported by `Metrics`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetricValue {
+    /// A change to a counter.
+    Count(i64),
+    /// How long something took.
+    Time(Duration),
+}
+
+/// Sends metrics to statsd, and records them for Prometheus when that exporter
+/// is enabled.
+#[derive(Debug)]
+pub struct Metrics {
+    statsd: StatsdClient,
+    prometheus: Option<Arc<Registry>>,
+    /// Names of the metrics that Prometheus has rejected, so that each one is
+    /// only logged once.
+    rejected: Mutex<HashSet<String>>,
+    log: slog::Logger,
+}
+
+impl Metrics {
+    pub fn new(statsd: StatsdClient, prometheus: Option<Arc<Registry>>, log: slog::Logger) -> Self {
+        Self {
+            statsd,
+            prometheus,
+            rejected: Mutex::new(HashSet::new()),
+            log,
+        }
+    }
+
+    /// Send metrics only to statsd, through `sink`.
+    pub fn from_sink<T>(prefix: &str, sink: T) -> Self
+    where
+        T: MetricSink + Sync + Send + RefUnwindSafe + 'static,
+    {
+        Self::new(
+            StatsdClient::from_sink(prefix, sink),
+            None,
+            slog::Logger::root(slog::Discard, slog::o!()),
+        )
+    }
+
+    pub fn incr_with_tags<'a>(&'a self, name: &'a str) -> MetricBuilder<'a> {
+        MetricBuilder::new(self, name, MetricValue::Count(1))
+    }
+
+    pub fn decr_with_tags<'a>(&'a self, name: &'a str) -> MetricBuilder<'a> {
+        MetricBuilder::new(self, name, MetricValue::Count(-1))
+    }
+
+    pub fn time_with_tags<'a>(&'a self, name: &'a str, duration: Duration) -> MetricBuilder<'a> {
+        MetricBuilder::new(self, name, MetricValue::Time(duration))
+    }
+}
+
+/// A metric that is being tagged, which is reported when it is sent.
+#[must_use = "metrics are only reported when sent"]
+pub struct MetricBuilder<'a> {
+    metrics: &'a Metrics,
+    name: &'a str,
+    value: MetricValue,
+    tags: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> MetricBuilder<'a> {
+    fn new(metrics: &'a Metrics, name: &'a str, value: MetricValue) -> Self {
+        Self {
+            metrics,
+            name,
+            value,
+            tags: Vec::new(),
+        }
+    }
+
+    pub fn with_tag(mut self, key: &'a str, value: &'a str) -> Self {
+        self.tags.push((key, value));
+        self
+    }
+
+    /// Report the metric. Errors are passed to the statsd client's error
+    /// handler. Metrics that don't fit the Prometheus metric of the same name
+    /// are left out of Prometheus, and logged the first time it happens for
+    /// each name.
+    pub fn send(self) {
+        let statsd = &self.metrics.statsd;
+        match self.value {
+            MetricValue::Count(count) => {
+                send_tagged(statsd.count_with_tags(self.name, count), &self.tags)
+            }
+            MetricValue::Time(duration) => {
+                send_tagged(statsd.time_with_tags(self.name, duration), &self.tags)
+            }
+        }
+        if let Some(registry) = &self.metrics.prometheus {
+            if let Err(err) = registry.record(self.name, self.value, &self.tags) {
+                let first = self
+                    .metrics
+                    .rejected
+                    .lock()
+                    .expect("rejected metrics lock poisoned")
+                    .insert(self.name.to_owned());
+                if first {
+                    slog::error!(
+                        self.metrics.log,
+                        "Could not record metric {} for Prometheus: {}",
+                        self.name,
+                        err
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn send_tagged<'m, T>(mut builder: cadence::MetricBuilder<'m, '_, T>, tags: &[(&'m str, &'m str)])
+where
+    T: cadence::Metric + From<String>,
+{
+    for (key, value) in tags {
+        builder = builder.with_tag(key, value);
+    }
+    builder.send();
+}
+
+pub fn get_client<A>(
+    metrics_target: A,
+    exporter: MetricsExporter,
+    registry: Arc<Registry>,
+    log: slog::Logger,
+) -> Result<Metrics, ClassifyError>
 where
     A: ToSocketAddrs + Display,
 {
-    let builder = {
+    let statsd_sink = if exporter.statsd() {
         // Bind a socket to any/all interfaces (0.0.0.0) and an arbitrary
         // port, chosen by the OS (indicated by port 0). This port is used
         // only to send metrics data, and isn't used to receive anything.
@@ -25,10 +203,7 @@ where
         let socket = UdpSocket::bind("0.0.0.0:0")?;
         socket.set_nonblocking(true)?;
         match BufferedUdpMetricSink::from(&metrics_target, socket) {
-            Ok(udp_sink) => {
-                let sink = cadence::QueuingMetricSink::from(udp_sink);
-                StatsdClient::builder(APP_NAME, sink)
-            }
+            Ok(udp_sink) => Some(cadence::QueuingMetricSink::from(udp_sink)),
             Err(err) => {
                 slog::error!(
                     log,
@@ -36,14 +211,28 @@ where
                     metrics_target,
                     err,
                 );
-                let sink = cadence::NopMetricSink;
-                StatsdClient::builder(APP_NAME, sink)
+                None
             }
         }
+    } else {
+        None
+    };
+
+    let builder = match statsd_sink {
+        Some(sink) => StatsdClient::builder(APP_NAME, sink),
+        None => StatsdClient::builder(APP_NAME, cadence::NopMetricSink),
     };
-    Ok(builder
-        .with_error_handler(move |error| slog::error!(log, "Could not send metric: {}", error))
-        .build())
+    let statsd_log = log.clone();
+    let statsd = builder
+        .with_error_handler(move |error| {
+            slog::error!(statsd_log, "Could not send metric: {}", error)
+        })
+        .build();
+    Ok(Metrics::new(
+        statsd,
+        exporter.prometheus().then_some(registry),
+        log,
+    ))
 }
 
 pub struct ResponseTimer;
@@ -87,32 +276,51 @@ where
             Some(state) => state.metrics.clone(),
             None => return Box::pin(self.service.call(req)),
         };
+        // Requests that don't match a route are grouped together, so that
+        // arbitrary paths don't each get their own series.
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_owned());
         let started = Instant::now();
 
         metrics.incr_with_tags("ongoing_requests").send();
 
-        Box::pin(self.service.call(req).then(move |res| match res {
-            Ok(val) => {
-                let duration = started.elapsed();
-                metrics
-                    .time_with_tags("response", duration)
-                    .with_tag(
-                        "status",
-                        if val.status().is_success() {
-                            "success"
-                        } else {
-                            "error"
-                        },
-                    )
-                    .send();
-                metrics.decr_with_tags("ongoing_requests").send();
-                future::ok(val)
-            }
-            Err(err) => future::err(err),
+        Box::pin(self.service.call(req).then(move |res| {
+            let status = match &res {
+                Ok(val) => val.status(),
+                Err(err) => err.as_response_error().status_code(),
+            };
+            metrics
+                .time_with_tags("response", started.elapsed())
+                .with_tag(
+                    "status",
+                    if status.is_success() {
+                        "success"
+                    } else {
+                        "error"
+                    },
+                )
+                .with_tag("route", &route)
+                .with_tag("status_class", status_class(status))
+                .with_tag("status_code", status.as_str())
+                .send();
+            metrics.decr_with_tags("ongoing_requests").send();
+            future::ready(res)
         }))
     }
 }
 
+/// Group a status code with similar ones, like "4xx".
+fn status_class(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
@@ -122,7 +330,6 @@ pub mod tests {
         web::{self, Data},
         App, HttpResponse,
     };
-    use cadence::StatsdClient;
     use regex::Regex;
     use std::{
         io,
@@ -147,7 +354,7 @@ pub mod tests {
         // Set up a service that logs metrics to vec we own
         let log = Arc::new(Mutex::new(Vec::new()));
         let state = EndpointState {
-            metrics: Arc::new(StatsdClient::from_sink(
+            metrics: Arc::new(Metrics::from_sink(
                 "test",
                 TestMetricSink { log: log.clone() },
             )),
@@ -180,13 +387,23 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_tag_values() {
+        let tags = TagValues::new(2);
+        assert_eq!(tags.get("a"), "a");
+        assert_eq!(tags.get("b"), "b");
+        assert_eq!(tags.get("c"), OTHER_TAG_VALUE);
+        assert_eq!(tags.get("a"), "a", "values seen before the limit are kept");
+        assert_eq!(tags.get("d"), OTHER_TAG_VALUE);
+    }
+
     /// Test that if a request fails, an error is reported in metrics
     #[actix_rt::test]
     async fn test_response_metrics_logs_error() -> Result<(), Box<dyn std::error::Error>> {
         // Set up a service that logs metrics to vec we own
         let log = Arc::new(Mutex::new(Vec::new()));
         let state = EndpointState {
-            metrics: Arc::new(StatsdClient::from_sink(
+            metrics: Arc::new(Metrics::from_sink(
                 "test",
                 TestMetricSink { log: log.clone() },
             )),
@@ -211,4 +428,85 @@ pub mod tests {
 
         Ok(())
     }
+
+    /// Test that responses are tagged with the route and status code
+    #[actix_rt::test]
+    async fn test_response_metrics_tags_route() -> Result<(), Box<dyn std::error::Error>> {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let state = EndpointState {
+            metrics: Arc::new(Metrics::from_sink(
+                "test",
+                TestMetricSink { log: log.clone() },
+            )),
+            ..EndpointState::default()
+        };
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .wrap(ResponseTimer)
+                .route("/items/{id}", web::get().to(HttpResponse::Unauthorized)),
+        )
+        .await;
+
+        for uri in ["/items/1", "/nowhere"] {
+            let request = TestRequest::with_uri(uri).to_request();
+            test::call_service(&service, request).await;
+        }
+
+        let log = log.lock().unwrap();
+        let route_re = Regex::new(
+            r"^test\.response:\d+\|ms\|#status:error,route:/items/\{id\},status_class:4xx,status_code:401$",
+        )?;
+        assert!(route_re.is_match(&log[1]), "unexpected metric {}", log[1]);
+        let unmatched_re = Regex::new(
+            r"^test\.response:\d+\|ms\|#status:error,route:unmatched,status_class:4xx,status_code:404$",
+        )?;
+        assert!(
+            unmatched_re.is_match(&log[4]),
+            "unexpected metric {}",
+            log[4]
+        );
+
+        Ok(())
+    }
+
+    /// Test that `ongoing_requests` goes back down when an inner service fails
+    #[actix_rt::test]
+    async fn test_response_metrics_service_error() -> Result<(), Box<dyn std::error::Error>> {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let state = EndpointState {
+            metrics: Arc::new(Metrics::from_sink(
+                "test",
+                TestMetricSink { log: log.clone() },
+            )),
+            ..EndpointState::default()
+        };
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .wrap_fn(|_req, _service| {
+                    future::err::<ServiceResponse, _>(actix_web::error::ErrorServiceUnavailable(
+                        "unavailable",
+                    ))
+                })
+                .wrap(ResponseTimer)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let request = TestRequest::with_uri("/").to_request();
+        assert!(test::try_call_service(&service, request).await.is_err());
+
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), 3);
+        assert_eq!(log[0], "test.ongoing_requests:1|c");
+        assert!(
+            log[1].ends_with("|ms|#status:error,route:/,status_class:5xx,status_code:503"),
+            "unexpected metric {}",
+            log[1]
+        );
+        assert_eq!(log[2], "test.ongoing_requests:-1|c");
+
+        Ok(())
+    }
 }
@@ -0,0 +1,137 @@
+use crate::{errors::ClassifyError, geoip::Location};
+use ipnet::IpNet;
+use serde_derive::Deserialize;
+use std::{
+    fs::read_to_string,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+/// A country to report for every address in `network`, regardless of what the
+/// GeoIP databases say.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct Override {
+    pub network: IpNet,
+    pub country_code: String,
+    pub country_name: Option<String>,
+}
+
+/// A set of locally configured overrides for IP ranges, loaded from a JSON
+/// file like
+///
+/// ```json
+/// [
+///   {"network": "10.0.0.0/8", "country_code": "US", "country_name": "United States"},
+///   {"network": "2001:db8::/32", "country_code": "DE"}
+/// ]
+/// ```
+#[derive(Debug)]
+pub struct Overrides {
+    path: PathBuf,
+    /// Sorted from most to least specific network, so that the first match
+    /// is the best one.
+    entries: RwLock<Arc<Vec<Override>>>,
+}
+
+impl Overrides {
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self, ClassifyError> {
+        let path = path.into();
+        let entries = Self::read(&path)?;
+        Ok(Self {
+            path,
+            entries: RwLock::new(Arc::new(entries)),
+        })
+    }
+
+    fn read(path: &Path) -> Result<Vec<Override>, ClassifyError> {
+        let contents = read_to_string(path)?;
+        let mut entries: Vec<Override> = serde_json::from_str(&contents)?;
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.network.prefix_len()));
+        Ok(entries)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read the file again. If it can't be read, the previous overrides stay
+    /// in place.
+    pub fn reload(&self) -> Result<(), ClassifyError> {
+        let entries = Self::read(&self.path)
+            .map_err(|err| ClassifyError::from_source(self.path.display(), err))?;
+        *self.entries.write().expect("overrides lock poisoned") = Arc::new(entries);
+        Ok(())
+    }
+
+    /// Find the most specific override that covers `ip`.
+    pub fn lookup(&self, ip: IpAddr) -> Option<Location> {
+        let entries = self
+            .entries
+            .read()
+            .expect("overrides lock poisoned")
+            .clone();
+        entries
+            .iter()
+            .find(|entry| entry.network.contains(&ip))
+            .map(|entry| Location {
+                country_code: Some(entry.country_code.clone()),
+                country_name: entry.country_name.clone(),
+                ..Location::default()
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Overrides;
+    use crate::reload::tests::TempDir;
+    use std::fs;
+
+    #[test]
+    fn test_overrides() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new();
+        let path = dir.join("overrides.json");
+        fs::write(
+            &path,
+            r#"[
+                {"network": "10.0.0.0/8", "country_code": "US", "country_name": "United States"},
+                {"network": "10.1.0.0/16", "country_code": "CA"},
+                {"network": "2001:db8::/32", "country_code": "DE"}
+            ]"#,
+        )?;
+        let overrides = Overrides::open(&path)?;
+
+        let location = overrides.lookup("10.2.3.4".parse()?).unwrap();
+        assert_eq!(location.country_code.as_deref(), Some("US"));
+        assert_eq!(location.country_name.as_deref(), Some("United States"));
+        assert_eq!(
+            overrides.lookup("10.1.3.4".parse()?).unwrap().country_code,
+            Some("CA".to_owned()),
+            "the most specific network should win"
+        );
+        assert_eq!(
+            overrides
+                .lookup("2001:db8::1".parse()?)
+                .unwrap()
+                .country_code,
+            Some("DE".to_owned())
+        );
+        assert_eq!(overrides.lookup("11.0.0.1".parse()?), None);
+
+        // A broken file keeps the previous overrides
+        fs::write(&path, r#"[{"network": "not a network""#)?;
+        assert!(overrides.reload().is_err());
+        assert!(overrides.lookup("10.2.3.4".parse()?).is_some());
+
+        fs::write(
+            &path,
+            r#"[{"network": "11.0.0.0/8", "country_code": "FR"}]"#,
+        )?;
+        overrides.reload()?;
+        assert_eq!(overrides.lookup("10.2.3.4".parse()?), None);
+        assert!(overrides.lookup("11.0.0.1".parse()?).is_some());
+
+        Ok(())
+    }
+}
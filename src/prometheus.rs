@@ -0,0 +1,364 @@
+use crate::metrics::MetricValue;
+use actix_web::{web::Data, HttpResponse};
+use prometheus::{
+    Error, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, TextEncoder,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+/// Histogram buckets for timers, in seconds.
+const TIMER_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Clone)]
+enum Family {
+    Counter(IntCounterVec),
+    Gauge(IntGaugeVec),
+    Histogram(HistogramVec),
+}
+
+/// Metrics collected in memory, to be scraped by Prometheus.
+///
+/// `Metrics` records every metric here as well as sending it to statsd. Each
+/// metric is registered the first time it is recorded, with the names of its
+/// tags as labels. Counters become Prometheus counters, timers become
+/// histograms in seconds, and tags become labels.
+pub struct Registry {
+    /// Prefixed to the name of every metric.
+    namespace: String,
+    /// Counters that go down as well as up, which are exposed as gauges.
+    up_down_counters: HashSet<String>,
+    registry: prometheus::Registry,
+    families: Mutex<HashMap<String, Family>>,
+}
+
+impl Registry {
+    pub fn new<I, S>(namespace: &str, up_down_counters: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            namespace: namespace.to_owned(),
+            up_down_counters: up_down_counters.into_iter().map(Into::into).collect(),
+            registry: prometheus::Registry::new(),
+            families: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record `value` for the metric called `name`. A metric must always be
+    /// recorded with the same kind of value and the same tag names, and only
+    /// up-down counters may be decremented. Anything else is an error, and is
+    /// not recorded.
+    pub fn record(
+        &self,
+        name: &str,
+        value: MetricValue,
+        tags: &[(&str, &str)],
+    ) -> Result<(), Error> {
+        let family = {
+            let mut families = self.families.lock().expect("registry lock poisoned");
+            match families.get(name) {
+                Some(family) => family.clone(),
+                None => {
+                    let family = self.register(name, value, tags)?;
+                    families.insert(name.to_owned(), family.clone());
+                    family
+                }
+            }
+        };
+
+        let labels: HashMap<&str, &str> = tags.iter().copied().collect();
+        match (family, value) {
+            (Family::Counter(counter), MetricValue::Count(count)) => {
+                let count = u64::try_from(count)
+                    .map_err(|_| Error::Msg(format!("counter {} can't go down", name)))?;
+                counter.get_metric_with(&labels)?.inc_by(count);
+            }
+            (Family::Gauge(gauge), MetricValue::Count(count)) => {
+                gauge.get_metric_with(&labels)?.add(count);
+            }
+            (Family::Histogram(histogram), MetricValue::Time(duration)) => {
+                histogram
+                    .get_metric_with(&labels)?
+                    .observe(duration.as_secs_f64());
+            }
+            _ => {
+                return Err(Error::Msg(format!(
+                    "{} was recorded with a different kind of value before",
+                    name
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    fn register(
+        &self,
+        name: &str,
+        value: MetricValue,
+        tags: &[(&str, &str)],
+    ) -> Result<Family, Error> {
+        let label_names: Vec<&str> = tags.iter().map(|(key, _)| *key).collect();
+        let family = match value {
+            MetricValue::Count(_) if self.up_down_counters.contains(name) => {
+                Family::Gauge(IntGaugeVec::new(
+                    Opts::new(name, name).namespace(&self.namespace),
+                    &label_names,
+                )?)
+            }
+            MetricValue::Count(_) => Family::Counter(IntCounterVec::new(
+                Opts::new(format!("{}_total", name), name).namespace(&self.namespace),
+                &label_names,
+            )?),
+            MetricValue::Time(_) => Family::Histogram(HistogramVec::new(
+                HistogramOpts::new(format!("{}_seconds", name), name)
+                    .namespace(&self.namespace)
+                    .buckets(TIMER_BUCKETS.to_vec()),
+                &label_names,
+            )?),
+        };
+        match &family {
+            Family::Counter(counter) => self.registry.register(Box::new(counter.clone()))?,
+            Family::Gauge(gauge) => self.registry.register(Box::new(gauge.clone()))?,
+            Family::Histogram(histogram) => self.registry.register(Box::new(histogram.clone()))?,
+        }
+        Ok(family)
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> Result<String, Error> {
+        TextEncoder::new().encode_to_string(&self.registry.gather())
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new("classify_client", ["ongoing_requests"])
+    }
+}
+
+// Metric vectors don't implement Debug, so we can't use #[derive(Debug)].
+impl fmt::Debug for Registry {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "Registry {{ namespace: {:?}, up_down_counters: {:?} }}",
+            self.namespace, self.up_down_counters
+        )
+    }
+}
+
+/// Serve the metrics in `registry` for Prometheus to scrape.
+pub async fn metrics(registry: Data<Arc<Registry>>) -> HttpResponse {
+    match registry.render() {
+        Ok(rendered) => HttpResponse::Ok()
+            .content_type(prometheus::TEXT_FORMAT)
+            .body(rendered),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Registry;
+    use crate::{
+        keys::tests::VecDrain,
+        metrics::{tests::TestMetricSink, MetricValue, Metrics},
+    };
+    use actix_web::{
+        test::{self, TestRequest},
+        web::{self, Data},
+        App,
+    };
+    use cadence::StatsdClient;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[test]
+    fn test_registry_renders_metrics() {
+        let registry = Registry::default();
+        let count = MetricValue::Count(1);
+        registry
+            .record(
+                "location",
+                count,
+                &[("country", "US"), ("database", "GeoLite2-Country")],
+            )
+            .unwrap();
+        registry
+            .record(
+                "location",
+                count,
+                &[("database", "GeoLite2-Country"), ("country", "US")],
+            )
+            .unwrap();
+        registry.record("country_hit", count, &[]).unwrap();
+        registry.record("ongoing_requests", count, &[]).unwrap();
+        registry.record("ongoing_requests", count, &[]).unwrap();
+        registry
+            .record("ongoing_requests", MetricValue::Count(-1), &[])
+            .unwrap();
+        for millis in [3, 300] {
+            registry
+                .record(
+                    "response",
+                    MetricValue::Time(Duration::from_millis(millis)),
+                    &[("status", "success")],
+                )
+                .unwrap();
+        }
+        // Tag values are passed on as they are, whatever they contain
+        registry
+            .record("odd", count, &[("path", "\"/a,b|c:d=e\\\"")])
+            .unwrap();
+
+        let rendered = registry.render().unwrap();
+        let expected = [
+            "# TYPE classify_client_country_hit_total counter",
+            "classify_client_country_hit_total 1",
+            "# TYPE classify_client_location_total counter",
+            "classify_client_location_total{country=\"US\",database=\"GeoLite2-Country\"} 2",
+            "# TYPE classify_client_odd_total counter",
+            "classify_client_odd_total{path=\"\\\"/a,b|c:d=e\\\\\\\"\"} 1",
+            "# TYPE classify_client_ongoing_requests gauge",
+            "classify_client_ongoing_requests 1",
+            "# TYPE classify_client_response_seconds histogram",
+            "classify_client_response_seconds_bucket{status=\"success\",le=\"0.001\"} 0",
+            "classify_client_response_seconds_bucket{status=\"success\",le=\"0.005\"} 1",
+            "classify_client_response_seconds_bucket{status=\"success\",le=\"0.25\"} 1",
+            "classify_client_response_seconds_bucket{status=\"success\",le=\"0.5\"} 2",
+            "classify_client_response_seconds_bucket{status=\"success\",le=\"+Inf\"} 2",
+            "classify_client_response_seconds_sum{status=\"success\"} 0.303",
+            "classify_client_response_seconds_count{status=\"success\"} 2",
+        ];
+        for line in expected {
+            assert!(
+                rendered.lines().any(|rendered| rendered == line),
+                "missing {:?} in\n{}",
+                line,
+                rendered
+            );
+        }
+    }
+
+    #[test]
+    fn test_registry_rejects_inconsistent_metrics() {
+        let registry = Registry::default();
+        let count = MetricValue::Count(1);
+        registry.record("hit", count, &[("country", "US")]).unwrap();
+
+        assert!(registry.record("hit", count, &[("api_key", "a")]).is_err());
+        assert!(registry.record("hit", count, &[]).is_err());
+        assert!(registry
+            .record("hit", MetricValue::Count(-1), &[("country", "US")])
+            .is_err());
+        assert!(registry
+            .record(
+                "hit",
+                MetricValue::Time(Duration::ZERO),
+                &[("country", "US")]
+            )
+            .is_err());
+
+        let rendered = registry.render().unwrap();
+        assert!(rendered.contains("classify_client_hit_total{country=\"US\"} 1\n"));
+    }
+
+    #[test]
+    fn test_metrics_records_both() {
+        let registry = Arc::new(Registry::default());
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let metrics = Metrics::new(
+            StatsdClient::from_sink("test", TestMetricSink { log: log.clone() }),
+            Some(registry.clone()),
+            slog::Logger::root(slog::Discard, slog::o!()),
+        );
+
+        metrics.incr_with_tags("country_miss").send();
+        metrics
+            .time_with_tags("response", Duration::from_millis(20))
+            .with_tag("status", "error")
+            .send();
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["test.country_miss:1|c", "test.response:20|ms|#status:error"]
+        );
+        let rendered = registry.render().unwrap();
+        assert!(rendered.contains("classify_client_country_miss_total 1\n"));
+        assert!(rendered.contains("classify_client_response_seconds_count{status=\"error\"} 1\n"));
+    }
+
+    #[test]
+    fn test_metrics_logs_rejected_once() {
+        let registry = Arc::new(Registry::default());
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let logs = Arc::new(Mutex::new(Vec::new()));
+        let metrics = Metrics::new(
+            StatsdClient::from_sink("test", TestMetricSink { log: log.clone() }),
+            Some(registry.clone()),
+            slog::Logger::root(slog::Fuse::new(VecDrain { logs: logs.clone() }), slog::o!()),
+        );
+
+        metrics
+            .incr_with_tags("hit")
+            .with_tag("country", "US")
+            .send();
+        for _ in 0..3 {
+            metrics
+                .incr_with_tags("hit")
+                .with_tag("api_key", "a")
+                .send();
+        }
+        metrics.incr_with_tags("miss").send();
+        metrics
+            .time_with_tags("miss", Duration::from_millis(1))
+            .send();
+
+        // Statsd still gets every metric
+        assert_eq!(log.lock().unwrap().len(), 6);
+        let logs = logs.lock().unwrap();
+        assert_eq!(logs.len(), 2, "{:?}", *logs);
+        assert!(logs[0].starts_with("ERRO / Could not record metric hit for Prometheus"));
+        assert!(logs[1].starts_with("ERRO / Could not record metric miss for Prometheus"));
+    }
+
+    #[actix_rt::test]
+    async fn test_metrics_endpoint() {
+        let registry = Arc::new(Registry::default());
+        let metrics = Metrics::new(
+            StatsdClient::from_sink("test", cadence::NopMetricSink),
+            Some(registry.clone()),
+            slog::Logger::root(slog::Discard, slog::o!()),
+        );
+        metrics.incr_with_tags("country_hit").send();
+
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(registry))
+                .route("/metrics", web::get().to(super::metrics)),
+        )
+        .await;
+        let request = TestRequest::get().uri("/metrics").to_request();
+        let response = test::call_service(&service, request).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            "text/plain; version=0.0.4"
+        );
+        let body = test::read_body(response).await;
+        assert_eq!(
+            body,
+            "# HELP classify_client_country_hit_total country_hit\n\
+             # TYPE classify_client_country_hit_total counter\n\
+             classify_client_country_hit_total 1\n"
+        );
+    }
+}
@@ -0,0 +1,525 @@
+use crate::{endpoints::EndpointState, errors::ClassifyError, utils::RequestClientIp};
+use actix_web::{
+    body::EitherBody,
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderValue, RETRY_AFTER},
+    web::Data,
+    Error, HttpResponse,
+};
+use futures::{future, Future, FutureExt};
+use ipnet::IpNet;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fmt,
+    hash::Hash,
+    net::IpAddr,
+    pin::Pin,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How quickly requests may be made, as a token bucket. Each request takes a
+/// token, tokens are refilled at `requests_per_second`, and at most `burst`
+/// tokens can be saved up.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct RateLimit {
+    pub requests_per_second: f64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    pub fn new(requests_per_second: f64, burst: u32) -> Self {
+        Self {
+            requests_per_second,
+            burst,
+        }
+    }
+
+    fn capacity(&self) -> f64 {
+        // A burst of zero would block every request, so always allow at least one.
+        f64::from(self.burst.max(1))
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * self.limit.requests_per_second).min(self.limit.capacity());
+        self.updated = now;
+    }
+
+    /// Whether the bucket would have refilled completely by `now`, which
+    /// makes it the same as a new one.
+    fn is_full_at(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * self.limit.requests_per_second >= self.limit.capacity()
+    }
+}
+
+/// Token buckets for many clients, identified by keys of type `K`.
+#[derive(Debug)]
+pub struct RateLimiter<K> {
+    buckets: Mutex<HashMap<K, Bucket>>,
+    /// The most buckets that are tracked at once. See `make_space`.
+    max_buckets: usize,
+}
+
+impl<K> Default for RateLimiter<K>
+where
+    K: Eq + Hash,
+{
+    fn default() -> Self {
+        Self::new(10_000)
+    }
+}
+
+impl<K> RateLimiter<K>
+where
+    K: Eq + Hash,
+{
+    pub fn new(max_buckets: usize) -> Self {
+        Self {
+            buckets: Mutex::new(HashMap::new()),
+            max_buckets,
+        }
+    }
+
+    /// Take a token from the bucket for `key`. If there are none left, return
+    /// how long until there will be one.
+    pub fn check(&self, key: K, limit: &RateLimit, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().expect("rate limiter lock poisoned");
+
+        if buckets.len() >= self.max_buckets && !buckets.contains_key(&key) {
+            self.make_space(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(key).or_insert_with(|| Bucket {
+            limit: *limit,
+            tokens: limit.capacity(),
+            updated: now,
+        });
+        bucket.refill(now);
+        // Limits can change when configuration is reloaded.
+        bucket.limit = *limit;
+        bucket.tokens = bucket.tokens.min(limit.capacity());
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if limit.requests_per_second > 0.0 {
+            let missing = 1.0 - bucket.tokens;
+            Err(
+                Duration::try_from_secs_f64(missing / limit.requests_per_second)
+                    .unwrap_or(Duration::MAX),
+            )
+        } else {
+            Err(Duration::MAX)
+        }
+    }
+
+    /// Forget buckets until at most three quarters of `max_buckets` are left,
+    /// so that the cost of going through all of them is spread over many new
+    /// keys. Buckets that have refilled completely go first, since nothing is
+    /// lost by forgetting them, followed by the least recently used ones.
+    fn make_space(&self, buckets: &mut HashMap<K, Bucket>, now: Instant) {
+        buckets.retain(|_, bucket| !bucket.is_full_at(now));
+
+        let keep = self.max_buckets / 4 * 3;
+        if buckets.len() > keep {
+            let mut updated: Vec<Instant> = buckets.values().map(|bucket| bucket.updated).collect();
+            let evict = buckets.len() - keep;
+            let (_, cutoff, _) = updated.select_nth_unstable(evict - 1);
+            let cutoff = *cutoff;
+            buckets.retain(|_, bucket| bucket.updated > cutoff);
+        }
+    }
+}
+
+/// A `Retry-After` header value for a client that must wait `duration`.
+pub fn retry_after(duration: Duration) -> HeaderValue {
+    HeaderValue::from(duration.as_secs_f64().ceil() as u64)
+}
+
+/// A rate limit for requests to one path from each client. In settings, this
+/// is written as `path=requests_per_second:burst`, like `/=10:20`. If the
+/// burst is left out, it is the number of requests allowed per second.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RouteRateLimit {
+    pub path: String,
+    pub limit: RateLimit,
+}
+
+impl FromStr for RouteRateLimit {
+    type Err = ClassifyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            ClassifyError::new(format!(
+                "Invalid rate limit {:?}, expected path=requests_per_second:burst",
+                s
+            ))
+        };
+
+        let (path, limit) = s.trim().rsplit_once('=').ok_or_else(invalid)?;
+        let (rate, burst) = match limit.split_once(':') {
+            Some((rate, burst)) => (rate, Some(burst)),
+            None => (limit, None),
+        };
+        let rate: f64 = rate.parse().map_err(|_| invalid())?;
+        if !path.starts_with('/') || !rate.is_finite() || rate < 0.0 {
+            return Err(invalid());
+        }
+        let burst = match burst {
+            Some(burst) => burst.parse().map_err(|_| invalid())?,
+            None => rate.ceil() as u32,
+        };
+
+        Ok(Self {
+            path: path.to_owned(),
+            limit: RateLimit::new(rate, burst),
+        })
+    }
+}
+
+impl TryFrom<String> for RouteRateLimit {
+    type Error = ClassifyError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for RouteRateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}={}:{}",
+            self.path, self.limit.requests_per_second, self.limit.burst
+        )
+    }
+}
+
+impl From<RouteRateLimit> for String {
+    fn from(value: RouteRateLimit) -> Self {
+        value.to_string()
+    }
+}
+
+/// Rate limits for each client IP, per route. IPv6 clients are limited per
+/// /64 network, since each one usually has a whole network to pick from.
+#[derive(Debug, Default)]
+pub struct ClientRateLimits {
+    routes: HashMap<String, RateLimit>,
+    /// Clients in these networks are never limited.
+    exempt: Vec<IpNet>,
+    limiter: RateLimiter<(String, IpAddr)>,
+}
+
+impl ClientRateLimits {
+    pub fn new<I: IntoIterator<Item = RouteRateLimit>>(routes: I, exempt: Vec<IpNet>) -> Self {
+        Self {
+            routes: routes
+                .into_iter()
+                .map(|route| (route.path, route.limit))
+                .collect(),
+            exempt,
+            limiter: RateLimiter::default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// Take a token for a request from `ip` to the route matching `pattern`.
+    /// If the client has made too many requests, return how long until it may
+    /// make another one.
+    pub fn check(&self, pattern: &str, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let limit = match self.routes.get(pattern) {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        if self.exempt.iter().any(|network| network.contains(&ip)) {
+            return Ok(());
+        }
+        let client = match ip {
+            IpAddr::V4(_) => ip,
+            IpAddr::V6(_) => IpNet::new(ip, 64)
+                .expect("64 is a valid IPv6 prefix length")
+                .network(),
+        };
+        self.limiter.check((pattern.to_owned(), client), limit, now)
+    }
+}
+
+/// Middleware that rejects requests from clients that have gone over the
+/// limits in `EndpointState::client_rate_limits`. Clients are identified by
+/// the same IP that the endpoints use, so proxies don't share a bucket.
+pub struct ClientRateLimit;
+
+impl<S, B> Transform<S, ServiceRequest> for ClientRateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ClientRateLimitMiddleware<S>;
+    type Future = future::Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(ClientRateLimitMiddleware { service })
+    }
+}
+
+pub struct ClientRateLimitMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for ClientRateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let state = match req.app_data::<Data<EndpointState>>() {
+            Some(state) if !state.client_rate_limits.is_empty() => state.clone(),
+            _ => {
+                return Box::pin(
+                    self.service
+                        .call(req)
+                        .map(|res| res.map(|res| res.map_into_left_body())),
+                )
+            }
+        };
+
+        // Limits are set per route, so that paths with different spellings or
+        // parameters share one. If the client can't be identified, let the
+        // endpoint deal with it.
+        let pattern = req.match_pattern();
+        if let (Some(pattern), Ok(ip)) = (&pattern, req.request().client_ip()) {
+            if let Err(wait) = state.client_rate_limits.check(pattern, ip, Instant::now()) {
+                state
+                    .metrics
+                    .incr_with_tags("client_throttled")
+                    .with_tag("path", pattern)
+                    .send();
+                let response = HttpResponse::TooManyRequests()
+                    .insert_header((RETRY_AFTER, retry_after(wait)))
+                    .body("Too many requests");
+                return Box::pin(future::ok(
+                    req.into_response(response).map_into_right_body(),
+                ));
+            }
+        }
+
+        Box::pin(
+            self.service
+                .call(req)
+                .map(|res| res.map(|res| res.map_into_left_body())),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientRateLimit, ClientRateLimits, RateLimit, RateLimiter, RouteRateLimit};
+    use crate::{
+        endpoints::EndpointState,
+        metrics::{tests::TestMetricSink, Metrics},
+    };
+    use actix_web::{
+        test::{self, TestRequest},
+        web::{self, Data},
+        App, HttpResponse,
+    };
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::default();
+        let limit = RateLimit::new(2.0, 3);
+        let start = Instant::now();
+
+        // The burst is available right away
+        for _ in 0..3 {
+            assert_eq!(limiter.check("a", &limit, start), Ok(()));
+        }
+        assert_eq!(
+            limiter.check("a", &limit, start),
+            Err(Duration::from_millis(500))
+        );
+
+        // Other keys have their own buckets
+        assert_eq!(limiter.check("b", &limit, start), Ok(()));
+
+        // Tokens refill over time
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.check("a", &limit, later), Ok(()));
+        assert!(limiter.check("a", &limit, later).is_err());
+    }
+
+    #[test]
+    fn test_rate_limiter_forgets_full_buckets() {
+        let limiter = RateLimiter::new(2);
+        let limit = RateLimit::new(1.0, 1);
+        let start = Instant::now();
+
+        assert_eq!(limiter.check("a", &limit, start), Ok(()));
+        assert_eq!(limiter.check("b", &limit, start), Ok(()));
+        // "a" and "b" have refilled by now, so they are dropped to make space
+        let later = start + Duration::from_secs(1);
+        assert_eq!(limiter.check("c", &limit, later), Ok(()));
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_rate_limiter_evicts_least_recently_used() {
+        let limiter = RateLimiter::new(4);
+        let limit = RateLimit::new(0.001, 10);
+        let start = Instant::now();
+
+        for (i, key) in ["a", "b", "c", "d"].into_iter().enumerate() {
+            let now = start + Duration::from_secs(i as u64);
+            assert_eq!(limiter.check(key, &limit, now), Ok(()));
+        }
+        // None of the buckets have refilled, so the oldest is dropped instead
+        let later = start + Duration::from_secs(4);
+        assert_eq!(limiter.check("e", &limit, later), Ok(()));
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), 4);
+        assert!(!buckets.contains_key("a"));
+    }
+
+    #[test]
+    fn test_rate_limiter_tiny_rate() {
+        let limiter = RateLimiter::default();
+        let limit = RateLimit::new(1e-300, 1);
+        let now = Instant::now();
+
+        assert_eq!(limiter.check("a", &limit, now), Ok(()));
+        assert_eq!(limiter.check("a", &limit, now), Err(Duration::MAX));
+    }
+
+    #[test]
+    fn test_parse_route_rate_limit() {
+        assert_eq!(
+            "/=10:20".parse::<RouteRateLimit>().unwrap(),
+            RouteRateLimit {
+                path: "/".to_owned(),
+                limit: RateLimit::new(10.0, 20),
+            }
+        );
+        assert_eq!(
+            "/v1/country=0.5".parse::<RouteRateLimit>().unwrap(),
+            RouteRateLimit {
+                path: "/v1/country".to_owned(),
+                limit: RateLimit::new(0.5, 1),
+            }
+        );
+        assert!("/".parse::<RouteRateLimit>().is_err());
+        assert!("country=1:2".parse::<RouteRateLimit>().is_err());
+        assert!("/=fast".parse::<RouteRateLimit>().is_err());
+        assert!("/=1:-2".parse::<RouteRateLimit>().is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_client_rate_limit() -> Result<(), Box<dyn std::error::Error>> {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let state = EndpointState {
+            client_rate_limits: Arc::new(ClientRateLimits::new(
+                vec!["/=1:2".parse()?, "/items/{id}=1:1".parse()?],
+                vec!["10.0.0.0/8".parse()?],
+            )),
+            metrics: Arc::new(Metrics::from_sink(
+                "test",
+                TestMetricSink { log: log.clone() },
+            )),
+            trusted_proxies: vec!["192.0.2.1/32".parse()?],
+            ..EndpointState::default()
+        };
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .wrap(ClientRateLimit)
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route("/other", web::get().to(HttpResponse::Ok))
+                .route("/items/{id}", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        // Clients behind the trusted proxy are told apart by X-Forwarded-For
+        let requests = [
+            ("/", "1.2.3.4", 200),
+            ("/", "1.2.3.4", 200),
+            ("/", "1.2.3.4", 429),
+            ("/", "5.6.7.8", 200),
+            ("/other", "1.2.3.4", 200),
+            ("/", "10.1.2.3", 200),
+            ("/", "10.1.2.3", 200),
+            ("/", "10.1.2.3", 200),
+            // IPv6 clients are limited by /64
+            ("/", "2001:db8::1", 200),
+            ("/", "2001:db8::2", 200),
+            ("/", "2001:db8::3", 429),
+            ("/", "2001:db8:0:1::1", 200),
+            // Limits apply to the route, whatever the path parameters are
+            ("/items/1", "1.2.3.4", 200),
+            ("/items/2", "1.2.3.4", 429),
+        ];
+        for (path, client, status) in requests {
+            let request = TestRequest::get()
+                .uri(path)
+                .peer_addr("192.0.2.1:8000".parse()?)
+                .insert_header(("x-forwarded-for", client))
+                .to_request();
+            let response = test::call_service(&service, request).await;
+            assert_eq!(
+                response.status(),
+                status,
+                "unexpected status for {} from {}",
+                path,
+                client
+            );
+            if status == 429 {
+                assert_eq!(response.headers().get("retry-after").unwrap(), "1");
+            }
+        }
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "test.client_throttled:1|c|#path:/",
+                "test.client_throttled:1|c|#path:/",
+                "test.client_throttled:1|c|#path:/items/{id}",
+            ]
+        );
+
+        Ok(())
+    }
+}
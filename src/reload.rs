@@ -0,0 +1,249 @@
+use crate::{errors::ClassifyError, metrics::Metrics};
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+/// Something that is loaded from files on disk and can be swapped out while
+/// the server is running.
+pub trait Reloadable: Send + Sync + 'static {
+    /// A short name for logs and metric tags, like "geoip".
+    fn name(&self) -> &'static str;
+
+    /// The files that this resource is loaded from. A change to the
+    /// modification time of any of them triggers a reload.
+    fn watched_paths(&self) -> Vec<PathBuf>;
+
+    /// Load the resource again. On failure, the previously loaded version
+    /// must stay in place.
+    fn reload(&self) -> Result<(), ClassifyError>;
+}
+
+/// Reload `target`, logging the outcome and reporting it as a `reload` metric.
+pub fn reload_and_report(
+    target: &dyn Reloadable,
+    log: &slog::Logger,
+    metrics: &Metrics,
+) -> Result<(), ClassifyError> {
+    let name = target.name();
+    match target.reload() {
+        Ok(()) => {
+            slog::info!(log, "Reloaded {}", name);
+            metrics
+                .incr_with_tags("reload")
+                .with_tag("target", name)
+                .with_tag("status", "success")
+                .send();
+            Ok(())
+        }
+        Err(err) => {
+            slog::error!(
+                log,
+                "Failed to reload {}, keeping the previous version. {}",
+                name,
+                err
+            );
+            metrics
+                .incr_with_tags("reload")
+                .with_tag("target", name)
+                .with_tag("status", "error")
+                .send();
+            Err(err)
+        }
+    }
+}
+
+/// Reload `target` whenever one of its files changes on disk, checking every
+/// `interval`, and whenever the process receives SIGHUP. A zero interval
+/// disables polling, leaving only the signal.
+///
+/// Must be called from within an actix runtime.
+pub fn spawn_watcher(
+    target: Arc<dyn Reloadable>,
+    interval: Duration,
+    log: slog::Logger,
+    metrics: Arc<Metrics>,
+) {
+    if !interval.is_zero() {
+        let target = Arc::clone(&target);
+        let log = log.clone();
+        let metrics = Arc::clone(&metrics);
+        actix_rt::spawn(async move {
+            let mut mtimes = modification_times(&target.watched_paths());
+            let mut ticker = actix_rt::time::interval(interval);
+            // The first tick completes immediately.
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let current = modification_times(&target.watched_paths());
+                if current != mtimes {
+                    // Only remember the new times if the reload worked, so
+                    // that a half-written file is picked up once it's complete.
+                    if reload_blocking(&target, &log, &metrics).await.is_ok() {
+                        mtimes = current;
+                    }
+                }
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    actix_rt::spawn(async move {
+        use actix_rt::signal::unix::{signal, SignalKind};
+
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(err) => {
+                slog::error!(log, "Could not listen for SIGHUP. {}", err);
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            let _ = reload_blocking(&target, &log, &metrics).await;
+        }
+    });
+}
+
+/// Run `reload_and_report` on the blocking thread pool, since loading large
+/// files would otherwise hold up everything else on the arbiter.
+async fn reload_blocking(
+    target: &Arc<dyn Reloadable>,
+    log: &slog::Logger,
+    metrics: &Arc<Metrics>,
+) -> Result<(), ClassifyError> {
+    let target = Arc::clone(target);
+    let log = log.clone();
+    let metrics = Arc::clone(metrics);
+    actix_rt::task::spawn_blocking(move || reload_and_report(target.as_ref(), &log, &metrics))
+        .await
+        .unwrap_or_else(|err| Err(ClassifyError::from_source("reload task", err)))
+}
+
+fn modification_times(paths: &[PathBuf]) -> HashMap<PathBuf, Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| {
+            let mtime = fs::metadata(path).and_then(|meta| meta.modified()).ok();
+            (path.clone(), mtime)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::metrics::tests::TestMetricSink;
+    use std::{
+        ops::Deref,
+        path::Path,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Mutex,
+        },
+    };
+
+    /// A directory for the files of one test, which is deleted when dropped,
+    /// even if the test fails.
+    pub struct TempDir {
+        path: PathBuf,
+    }
+
+    impl TempDir {
+        pub fn new() -> Self {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let path = std::env::temp_dir().join(format!(
+                "classify-client-test-{}-{}",
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::SeqCst)
+            ));
+            fs::create_dir_all(&path).expect("could not create temp dir");
+            Self { path }
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.path
+        }
+
+        pub fn join<P: AsRef<Path>>(&self, name: P) -> PathBuf {
+            self.path.join(name)
+        }
+    }
+
+    impl Default for TempDir {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    struct Flaky {
+        fail: AtomicBool,
+    }
+
+    impl Reloadable for Flaky {
+        fn name(&self) -> &'static str {
+            "flaky"
+        }
+
+        fn watched_paths(&self) -> Vec<PathBuf> {
+            Vec::new()
+        }
+
+        fn reload(&self) -> Result<(), ClassifyError> {
+            if self.fail.load(Ordering::SeqCst) {
+                Err(ClassifyError::new("nope"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn test_reload_and_report_sends_metrics() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let metrics = Metrics::from_sink("test", TestMetricSink { log: log.clone() });
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let target = Flaky {
+            fail: AtomicBool::new(false),
+        };
+
+        assert!(reload_and_report(&target, &logger, &metrics).is_ok());
+        target.fail.store(true, Ordering::SeqCst);
+        assert!(reload_and_report(&target, &logger, &metrics).is_err());
+
+        assert_eq!(
+            *log.lock().unwrap().deref(),
+            vec![
+                "test.reload:1|c|#target:flaky,status:success",
+                "test.reload:1|c|#target:flaky,status:error",
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_reload_blocking() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let metrics = Arc::new(Metrics::from_sink(
+            "test",
+            TestMetricSink { log: log.clone() },
+        ));
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let target: Arc<dyn Reloadable> = Arc::new(Flaky {
+            fail: AtomicBool::new(false),
+        });
+
+        assert!(reload_blocking(&target, &logger, &metrics).await.is_ok());
+        assert_eq!(
+            *log.lock().unwrap().deref(),
+            vec!["test.reload:1|c|#target:flaky,status:success"]
+        );
+    }
+}
@@ -1,15 +1,39 @@
-use crate::errors::ClassifyError;
+use crate::{errors::ClassifyError, keys::DownstreamKeys, ratelimit::RouteRateLimit};
 use serde_derive::{Deserialize, Serialize};
 use std::path::PathBuf;
 
-fn default_geoip_db_path() -> PathBuf {
-    "./GeoLite2-Country.mmdb".into()
+fn default_geoip_db_paths() -> Vec<PathBuf> {
+    vec!["./GeoLite2-Country.mmdb".into()]
+}
+
+fn default_geoip_reload_interval() -> u64 {
+    60
+}
+
+fn default_geoip_cache_ipv4_prefix_len() -> u8 {
+    32
+}
+
+fn default_geoip_cache_ipv6_prefix_len() -> u8 {
+    128
 }
 
 fn default_api_keys_file() -> PathBuf {
     "./apiKeys.json".into()
 }
 
+fn default_country_batch_max_size() -> usize {
+    1000
+}
+
+fn default_api_keys_reload_interval() -> u64 {
+    60
+}
+
+fn default_downstream_key_burst() -> u32 {
+    10
+}
+
 fn default_host() -> String {
     "[::]".to_owned()
 }
@@ -26,6 +50,46 @@ fn default_metrics_target() -> String {
     "localhost:8125".to_owned()
 }
 
+fn default_shutdown_grace_period() -> u64 {
+    0
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+fn default_keep_alive() -> u64 {
+    5
+}
+
+fn default_backlog() -> u32 {
+    2048
+}
+
+fn default_admin_port() -> u16 {
+    8001
+}
+
+fn default_tls_port() -> u16 {
+    8443
+}
+
+fn default_tls_reload_interval() -> u64 {
+    60
+}
+
+fn default_tls_handshake_timeout() -> u64 {
+    3
+}
+
+fn default_tracing_sample_rate() -> f64 {
+    1.0
+}
+
+fn default_metrics_max_tag_values() -> usize {
+    300
+}
+
 fn default_sentry_env() -> String {
     "production".to_owned()
 }
@@ -34,32 +98,277 @@ fn default_sentry_sample_rate() -> f32 {
     1.0
 }
 
+/// What `__heartbeat__` does when the GeoIP database is older than allowed.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StalePolicy {
+    /// Log a warning, but keep reporting the service as healthy.
+    #[default]
+    Warn,
+    /// Report the service as unavailable.
+    Fail,
+}
+
+/// Which header lists the proxies that a request passed through.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    /// `X-Forwarded-For`, a comma-separated list of addresses.
+    #[default]
+    XForwardedFor,
+    /// `Forwarded`, as in RFC 7239, using the `for` parameter of each element.
+    Forwarded,
+}
+
+/// What to do with `X-Forwarded-For` entries that aren't addresses.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InvalidHopPolicy {
+    /// Leave the entry out, and keep going.
+    #[default]
+    Skip,
+    /// Leave out the entry and everything before it, so that the last hop
+    /// before it is taken as the client if it isn't a trusted proxy.
+    Stop,
+    /// Fail to determine the client's address.
+    Fail,
+}
+
+impl InvalidHopPolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            InvalidHopPolicy::Skip => "skip",
+            InvalidHopPolicy::Stop => "stop",
+            InvalidHopPolicy::Fail => "fail",
+        }
+    }
+}
+
+/// Where to send metrics.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsExporter {
+    /// Send metrics to `metrics_target` over statsd.
+    #[default]
+    Statsd,
+    /// Serve metrics for Prometheus to scrape, on `admin_port`.
+    Prometheus,
+    /// Do both.
+    Both,
+}
+
+impl MetricsExporter {
+    pub fn statsd(self) -> bool {
+        matches!(self, MetricsExporter::Statsd | MetricsExporter::Both)
+    }
+
+    pub fn prometheus(self) -> bool {
+        matches!(self, MetricsExporter::Prometheus | MetricsExporter::Both)
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Settings {
     #[serde(default)]
     pub debug: bool,
 
-    #[serde(default = "default_geoip_db_path")]
-    pub geoip_db_path: PathBuf,
+    /// Include region-level fields, such as subdivision and city, in the
+    /// original classify endpoints. They are always included in the v2
+    /// endpoint.
+    #[serde(default)]
+    pub extended_classification: bool,
+
+    /// GeoIP databases to look up clients in. Each address is looked up in
+    /// these in order, and the first database to find a country wins. For
+    /// compatibility, this can also be set with `GEOIP_DB_PATH`.
+    #[serde(default = "default_geoip_db_paths", alias = "geoip_db_path")]
+    pub geoip_db_paths: Vec<PathBuf>,
+
+    /// The number of GeoIP lookup results to cache. Set to 0, the default, to
+    /// disable the cache.
+    #[serde(default)]
+    pub geoip_cache_size: usize,
+
+    /// Cache results for whole IPv4 networks of this size instead of single
+    /// addresses. Defaults to 32, meaning single addresses.
+    #[serde(default = "default_geoip_cache_ipv4_prefix_len")]
+    pub geoip_cache_ipv4_prefix_len: u8,
+
+    /// Cache results for whole IPv6 networks of this size instead of single
+    /// addresses. Defaults to 128, meaning single addresses.
+    #[serde(default = "default_geoip_cache_ipv6_prefix_len")]
+    pub geoip_cache_ipv6_prefix_len: u8,
+
+    /// A JSON file of IP ranges and the countries to report for them, which
+    /// take priority over the GeoIP databases.
+    pub geoip_overrides_path: Option<PathBuf>,
+
+    /// The country code to report for private addresses, like those in
+    /// 10.0.0.0/8, for example when internal services call the server. They
+    /// have no location if unset. Must be set with
+    /// `geoip_private_country_name`.
+    pub geoip_private_country_code: Option<String>,
+
+    /// The English name of the country in `geoip_private_country_code`.
+    pub geoip_private_country_name: Option<String>,
+
+    /// How often, in seconds, to check the GeoIP database file for changes
+    /// and reload it. Set to 0 to only reload on SIGHUP. Defaults to 60.
+    #[serde(default = "default_geoip_reload_interval")]
+    pub geoip_reload_interval: u64,
+
+    /// The number of days after its build date that the GeoIP database is
+    /// considered stale. Staleness is not checked if unset.
+    pub geoip_max_age_days: Option<u64>,
+
+    /// What the heartbeat does once the GeoIP database is stale.
+    #[serde(default)]
+    pub geoip_stale_policy: StalePolicy,
 
     #[serde(default = "default_api_keys_file")]
     pub api_keys_file: PathBuf,
 
+    /// How often, in seconds, to check the API keys file for changes and
+    /// reload it. Set to 0 to only reload on SIGHUP. Defaults to 60.
+    #[serde(default = "default_api_keys_reload_interval")]
+    pub api_keys_reload_interval: u64,
+
+    /// The largest number of IPs that may be looked up in one request to
+    /// `/v1/country/batch`. Defaults to 1000.
+    #[serde(default = "default_country_batch_max_size")]
+    pub country_batch_max_size: usize,
+
+    /// The patterns that self-selected downstream keys may match, as a JSON
+    /// array of objects with a `name`, used in metrics, and a regex
+    /// `pattern`. Defaults to Firefox downstream keys.
+    #[serde(default)]
+    pub downstream_key_patterns: DownstreamKeys,
+
+    /// How many requests per second each self-selected downstream key may
+    /// make to `/v1/country`. Every key has its own limit. Unlimited if unset.
+    /// Keys in the API keys file set their own limits.
+    pub downstream_key_rate_limit: Option<f64>,
+
+    /// How many requests a downstream key may make at once before the rate
+    /// limit applies. Defaults to 10.
+    #[serde(default = "default_downstream_key_burst")]
+    pub downstream_key_burst: u32,
+
+    /// Rate limits for each client IP, per route, like
+    /// `/=10:20,/v1/country=5:10`. Each entry is a path, the number of
+    /// requests per second, and how many requests may be made at once.
+    /// Routes that aren't listed are not limited.
+    #[serde(default)]
+    pub client_rate_limits: Vec<RouteRateLimit>,
+
+    /// Networks of clients that are never rate limited.
+    #[serde(default)]
+    pub client_rate_limit_exempt: Vec<ipnet::IpNet>,
+
     #[serde(default = "default_host")]
     pub host: String,
 
     #[serde(default = "default_port")]
     pub port: u16,
 
+    /// The number of worker threads. Defaults to the number of CPUs.
+    pub workers: Option<usize>,
+
+    /// How long, in seconds, to keep idle connections open. Set to 0 to
+    /// close connections after each request. Defaults to 5.
+    #[serde(default = "default_keep_alive")]
+    pub keep_alive: u64,
+
+    /// The most connections waiting to be accepted. Defaults to 2048.
+    #[serde(default = "default_backlog")]
+    pub backlog: u32,
+
+    /// After SIGTERM, how long, in seconds, `__lbheartbeat__` fails before
+    /// the server stops accepting connections, so that load balancers can
+    /// stop sending requests. Defaults to 0.
+    #[serde(default = "default_shutdown_grace_period")]
+    pub shutdown_grace_period: u64,
+
+    /// How long, in seconds, to wait for requests in progress to finish once
+    /// the server stops accepting connections. Defaults to 30.
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+
+    /// The port for the admin server, which serves `/metrics` when the
+    /// Prometheus exporter is enabled. Defaults to 8001.
+    #[serde(default = "default_admin_port")]
+    pub admin_port: u16,
+
+    /// The path to a PEM file with the TLS certificate, followed by any
+    /// intermediate certificates. If this and `tls_key_path` are set, HTTPS is
+    /// served on `tls_port`, in addition to plain HTTP on `port`. Requires the
+    /// `tls` feature.
+    pub tls_cert_path: Option<PathBuf>,
+
+    /// The path to a PEM file with the private key for `tls_cert_path`.
+    pub tls_key_path: Option<PathBuf>,
+
+    /// The port to serve HTTPS on. Defaults to 8443.
+    #[serde(default = "default_tls_port")]
+    pub tls_port: u16,
+
+    /// How often, in seconds, to check the TLS certificate and key for changes
+    /// and reload them. 0 disables polling. Defaults to 60.
+    #[serde(default = "default_tls_reload_interval")]
+    pub tls_reload_interval: u64,
+
+    /// How long, in seconds, a client has to complete the TLS handshake before
+    /// the connection is closed. Defaults to 3.
+    #[serde(default = "default_tls_handshake_timeout")]
+    pub tls_handshake_timeout: u64,
+
+    /// Which header to trust for the addresses of proxies and the client. The
+    /// other one is ignored.
+    #[serde(default)]
+    pub forwarded_header: ForwardedHeader,
+
     #[serde(default)]
     pub trusted_proxy_list: Vec<ipnet::IpNet>,
 
+    /// What to do with `X-Forwarded-For` entries that aren't addresses. By
+    /// default they are skipped, which lets a malformed entry hide a hop.
+    #[serde(default)]
+    pub invalid_hop_policy: InvalidHopPolicy,
+
+    /// The number of proxies in front of the server, for when their
+    /// addresses aren't known in advance. The client is taken to be this many
+    /// hops away, and `trusted_proxy_list` is ignored.
+    pub trusted_proxy_hops: Option<usize>,
+
+    /// A header set by a CDN to the client's address, like
+    /// "CF-Connecting-IP". It is used instead of `forwarded_header` when the
+    /// request comes directly from `trusted_client_ip_header_peers`.
+    pub trusted_client_ip_header: Option<String>,
+
+    /// The networks of the CDN that sets `trusted_client_ip_header`.
+    #[serde(default)]
+    pub trusted_client_ip_header_peers: Vec<ipnet::IpNet>,
+
     #[serde(default)]
     pub human_logs: bool,
 
+    /// Replace the `key` query parameter in logged request paths, so that API
+    /// keys don't end up in logs.
+    #[serde(default)]
+    pub log_redact_api_keys: bool,
+
     #[serde(default = "default_version_file")]
     pub version_file: PathBuf,
 
+    /// The OTLP/HTTP endpoint of an OpenTelemetry collector to send traces
+    /// to, like "http://localhost:4318". Tracing is disabled if unset.
+    pub otlp_endpoint: Option<String>,
+
+    /// The fraction of new traces to record. Requests with a `traceparent`
+    /// header follow the caller's sampling decision. Defaults to 1.0.
+    #[serde(default = "default_tracing_sample_rate")]
+    pub tracing_sample_rate: f64,
+
     pub sentry_dsn: Option<String>,
     #[serde(default = "default_sentry_env")]
     pub sentry_env: String,
@@ -71,6 +380,16 @@ pub struct Settings {
     /// required. Defaults to "localhost:8125".
     #[serde(default = "default_metrics_target")]
     pub metrics_target: String,
+
+    /// Whether to send metrics to statsd, serve them to Prometheus, or both.
+    #[serde(default)]
+    pub metrics_exporter: MetricsExporter,
+
+    /// The most distinct values reported for metrics tags that depend on
+    /// requests or configuration, like `api_key` and `country`. Values beyond
+    /// this are reported as "other". Defaults to 300.
+    #[serde(default = "default_metrics_max_tag_values")]
+    pub metrics_max_tag_values: usize,
 }
 
 impl Default for Settings {
@@ -93,26 +412,72 @@ impl Settings {
 
 #[cfg(test)]
 mod tests {
-    use std::env;
+    use std::{env, path::PathBuf};
 
-    use crate::settings::Settings;
+    use crate::{
+        ratelimit::{RateLimit, RouteRateLimit},
+        settings::{ForwardedHeader, InvalidHopPolicy, MetricsExporter, Settings, StalePolicy},
+    };
 
     #[test]
     fn test_default_settings() {
         let settings = Settings::default();
 
         assert!(!settings.debug);
+        assert!(!settings.extended_classification);
         assert_eq!(
-            settings.geoip_db_path.to_str(),
-            Some("./GeoLite2-Country.mmdb")
+            settings.geoip_db_paths,
+            vec![PathBuf::from("./GeoLite2-Country.mmdb")]
         );
+        assert_eq!(settings.geoip_cache_size, 0);
+        assert_eq!(settings.geoip_cache_ipv4_prefix_len, 32);
+        assert_eq!(settings.geoip_cache_ipv6_prefix_len, 128);
+        assert_eq!(settings.geoip_overrides_path, None);
+        assert_eq!(settings.geoip_private_country_code, None);
+        assert_eq!(settings.geoip_private_country_name, None);
+        assert_eq!(settings.geoip_reload_interval, 60);
+        assert_eq!(settings.geoip_max_age_days, None);
+        assert_eq!(settings.geoip_stale_policy, StalePolicy::Warn);
+        assert_eq!(settings.api_keys_reload_interval, 60);
+        assert_eq!(settings.country_batch_max_size, 1000);
+        assert_eq!(settings.downstream_key_rate_limit, None);
+        assert_eq!(settings.downstream_key_burst, 10);
+        assert_eq!(
+            settings
+                .downstream_key_patterns
+                .matching("firefox-downstream-foo"),
+            Some("firefox-downstream")
+        );
+        assert_eq!(settings.client_rate_limits, Vec::new());
+        assert_eq!(settings.client_rate_limit_exempt, Vec::new());
         assert_eq!(settings.host, "[::]");
         assert_eq!(settings.port, 8000);
+        assert_eq!(settings.admin_port, 8001);
+        assert_eq!(settings.workers, None);
+        assert_eq!(settings.keep_alive, 5);
+        assert_eq!(settings.backlog, 2048);
+        assert_eq!(settings.shutdown_grace_period, 0);
+        assert_eq!(settings.shutdown_timeout, 30);
+        assert_eq!(settings.tls_cert_path, None);
+        assert_eq!(settings.tls_key_path, None);
+        assert_eq!(settings.tls_port, 8443);
+        assert_eq!(settings.tls_reload_interval, 60);
+        assert_eq!(settings.tls_handshake_timeout, 3);
+        assert_eq!(settings.forwarded_header, ForwardedHeader::XForwardedFor);
         assert_eq!(settings.trusted_proxy_list, Vec::new());
+        assert_eq!(settings.invalid_hop_policy, InvalidHopPolicy::Skip);
+        assert_eq!(settings.trusted_proxy_hops, None);
+        assert_eq!(settings.trusted_client_ip_header, None);
+        assert_eq!(settings.trusted_client_ip_header_peers, Vec::new());
         assert!(!settings.human_logs);
+        assert!(!settings.log_redact_api_keys);
         assert_eq!(settings.version_file.to_str(), Some("./version.json"));
+        assert_eq!(settings.otlp_endpoint, None);
+        assert_eq!(settings.tracing_sample_rate, 1.0);
         assert_eq!(settings.sentry_dsn, None);
         assert_eq!(settings.metrics_target, "localhost:8125");
+        assert_eq!(settings.metrics_exporter, MetricsExporter::Statsd);
+        assert_eq!(settings.metrics_max_tag_values, 300);
     }
 
     #[test]
@@ -120,11 +485,90 @@ mod tests {
         env::set_var("DEBUG", "true");
         env::set_var("PORT", "8888");
         env::set_var("TRUSTED_PROXY_LIST", "2001:db8::/48,192.168.100.14/24");
+        env::set_var("GEOIP_STALE_POLICY", "fail");
+        env::set_var("METRICS_EXPORTER", "both");
+        env::set_var("FORWARDED_HEADER", "forwarded");
+        env::set_var(
+            "GEOIP_DB_PATHS",
+            "./GeoIP2-Country.mmdb,./GeoLite2-Country.mmdb",
+        );
 
         let settings = Settings::load().unwrap();
 
         assert!(settings.debug);
         assert_eq!(settings.port, 8888);
         assert_eq!(settings.trusted_proxy_list.len(), 2);
+        assert_eq!(settings.geoip_stale_policy, StalePolicy::Fail);
+        assert_eq!(settings.metrics_exporter, MetricsExporter::Both);
+        assert_eq!(settings.forwarded_header, ForwardedHeader::Forwarded);
+        assert_eq!(
+            settings.geoip_db_paths,
+            vec![
+                PathBuf::from("./GeoIP2-Country.mmdb"),
+                PathBuf::from("./GeoLite2-Country.mmdb")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_client_rate_limits() {
+        let env = vec![
+            (
+                "CLIENT_RATE_LIMITS".to_owned(),
+                "/=10:20,/v1/country=0.5".to_owned(),
+            ),
+            (
+                "CLIENT_RATE_LIMIT_EXEMPT".to_owned(),
+                "10.0.0.0/8".to_owned(),
+            ),
+        ];
+        let settings: Settings = envy::from_iter(env).unwrap();
+
+        assert_eq!(
+            settings.client_rate_limits,
+            vec![
+                RouteRateLimit {
+                    path: "/".to_owned(),
+                    limit: RateLimit::new(10.0, 20),
+                },
+                RouteRateLimit {
+                    path: "/v1/country".to_owned(),
+                    limit: RateLimit::new(0.5, 1),
+                },
+            ]
+        );
+        assert_eq!(settings.client_rate_limit_exempt.len(), 1);
+
+        let env = vec![("CLIENT_RATE_LIMITS".to_owned(), "/=often".to_owned())];
+        assert!(envy::from_iter::<_, Settings>(env).is_err());
+    }
+
+    #[test]
+    fn test_downstream_key_patterns() {
+        let env = vec![(
+            "DOWNSTREAM_KEY_PATTERNS".to_owned(),
+            r#"[{"name": "thunderbird", "pattern": "^thunderbird-\\w{1,40}$"}, {"name": "fenix", "pattern": "^fenix-"}]"#
+                .to_owned(),
+        )];
+        let settings: Settings = envy::from_iter(env).unwrap();
+
+        let patterns = settings.downstream_key_patterns;
+        assert_eq!(patterns.matching("thunderbird-beta"), Some("thunderbird"));
+        assert_eq!(patterns.matching("fenix-nightly"), Some("fenix"));
+        assert_eq!(patterns.matching("firefox-downstream-foo"), None);
+    }
+
+    #[test]
+    fn test_single_geoip_db_path() {
+        let env = vec![(
+            "GEOIP_DB_PATH".to_owned(),
+            "./GeoLite2-City.mmdb".to_owned(),
+        )];
+        let settings: Settings = envy::from_iter(env).unwrap();
+
+        assert_eq!(
+            settings.geoip_db_paths,
+            vec![PathBuf::from("./GeoLite2-City.mmdb")]
+        );
     }
 }
@@ -0,0 +1,103 @@
+use actix_web::dev::ServerHandle;
+use futures::future;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Stop `servers` gracefully when the process receives SIGTERM or SIGINT.
+/// See `drain`.
+///
+/// The servers should be built with signal handling disabled, so that this is
+/// the only thing that stops them. Must be called from within an actix runtime.
+pub fn spawn_handler(
+    servers: Vec<ServerHandle>,
+    draining: Arc<AtomicBool>,
+    grace_period: Duration,
+    log: slog::Logger,
+) {
+    actix_rt::spawn(async move {
+        #[cfg(unix)]
+        {
+            use actix_rt::signal::unix::{signal, SignalKind};
+
+            let mut terminate = match signal(SignalKind::terminate()) {
+                Ok(terminate) => terminate,
+                Err(err) => {
+                    slog::error!(log, "Could not listen for SIGTERM. {}", err);
+                    return;
+                }
+            };
+            let terminated = Box::pin(terminate.recv());
+            let interrupted = Box::pin(actix_rt::signal::ctrl_c());
+            let signal = match future::select(terminated, interrupted).await {
+                future::Either::Left(_) => "SIGTERM",
+                future::Either::Right(_) => "SIGINT",
+            };
+            slog::info!(log, "Received {}", signal);
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = actix_rt::signal::ctrl_c().await;
+            slog::info!(log, "Received Ctrl-C");
+        }
+
+        drain(&servers, &draining, grace_period, &log).await;
+    });
+}
+
+/// Shut down in two steps. First set `draining`, which makes
+/// `__lbheartbeat__` fail so that load balancers stop sending new requests,
+/// and wait for `grace_period`. Then stop the servers, which stop accepting
+/// connections and finish requests in progress, up to their shutdown timeout.
+pub async fn drain(
+    servers: &[ServerHandle],
+    draining: &AtomicBool,
+    grace_period: Duration,
+    log: &slog::Logger,
+) {
+    draining.store(true, Ordering::SeqCst);
+    slog::info!(
+        log,
+        "Draining, waiting {}s before stopping",
+        grace_period.as_secs()
+    );
+    actix_rt::time::sleep(grace_period).await;
+
+    slog::info!(log, "Stopping servers");
+    future::join_all(servers.iter().map(|server| server.stop(true))).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::drain;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+
+    #[actix_rt::test]
+    async fn test_drain() -> Result<(), Box<dyn std::error::Error>> {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let server = HttpServer::new(|| App::new().route("/", web::get().to(HttpResponse::Ok)))
+            .disable_signals()
+            .workers(1)
+            .bind("127.0.0.1:0")?
+            .run();
+        let handle = server.handle();
+        let running = actix_rt::spawn(server);
+
+        let draining = AtomicBool::new(false);
+        drain(&[handle], &draining, Duration::from_millis(10), &log).await;
+
+        assert!(draining.load(Ordering::SeqCst));
+        // The server has stopped
+        actix_rt::time::timeout(Duration::from_secs(5), running).await???;
+
+        Ok(())
+    }
+}
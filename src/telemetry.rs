@@ -0,0 +1,357 @@
+//! Optional distributed tracing, exported to an OpenTelemetry collector over
+//! OTLP/HTTP.
+//!
+//! Each request gets a server span, which continues the trace from an
+//! incoming W3C `traceparent` header if there is one. Work done for the
+//! request, like resolving the client IP and looking it up in the GeoIP
+//! databases, is recorded in child spans.
+
+use crate::{
+    endpoints::EndpointState,
+    errors::ClassifyError,
+    geoip::{GeoIp, Location},
+    APP_NAME,
+};
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::HeaderMap,
+    web::Data,
+    Error, HttpMessage, HttpRequest,
+};
+use futures::{future, Future, FutureExt};
+use opentelemetry::{
+    propagation::{Extractor, TextMapPropagator},
+    trace::{Span as _, SpanKind, Status, TraceContextExt, Tracer as _},
+    Context, KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    trace::{Sampler, SdkTracerProvider, Span},
+    Resource,
+};
+use std::{net::IpAddr, pin::Pin};
+
+/// Build a tracer provider that sends spans to the collector at `endpoint`,
+/// like `http://localhost:4318`, in batches from a background thread.
+/// `sample_rate` is the fraction of new traces to record. Traces continued
+/// from an incoming `traceparent` follow the caller's sampling decision.
+pub fn provider(endpoint: &str, sample_rate: f64) -> Result<SdkTracerProvider, ClassifyError> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build()
+        .map_err(|err| ClassifyError::from_source("OTLP exporter", err))?;
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            sample_rate,
+        ))))
+        .with_resource(Resource::builder().with_service_name(APP_NAME).build())
+        .build())
+}
+
+/// Reads the trace context of a request from its headers.
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+pub trait RequestSpans {
+    /// Start a span for part of the work done for a request, as a child of
+    /// the request's span. Returns `None` if tracing is disabled.
+    fn child_span(&self, name: &str) -> Option<Span>;
+}
+
+impl RequestSpans for HttpRequest {
+    fn child_span(&self, name: &str) -> Option<Span> {
+        let state = self.app_data::<Data<EndpointState>>()?;
+        let tracer = state.tracer.as_ref()?;
+        let parent = self
+            .extensions()
+            .get::<Context>()
+            .cloned()
+            .unwrap_or_default();
+        Some(tracer.start_with_context(name.to_owned(), &parent))
+    }
+}
+
+/// Look up `ip` in `geoip`, recording the outcome in a `GeoIp::locate` span.
+pub fn locate(
+    req: &HttpRequest,
+    geoip: &GeoIp,
+    ip: IpAddr,
+) -> Result<Option<Location>, ClassifyError> {
+    let mut span = req.child_span("GeoIp::locate");
+    let result = geoip.locate(ip);
+    if let Some(span) = &mut span {
+        let outcome = match &result {
+            Ok(Some(location)) if location.country_code.is_some() => "found",
+            Ok(_) => "not_found",
+            Err(err) => {
+                span.set_status(Status::error(err.to_string()));
+                "error"
+            }
+        };
+        span.set_attribute(KeyValue::new("geoip.outcome", outcome));
+        if let Ok(Some(Location {
+            country_code: Some(country_code),
+            ..
+        })) = &result
+        {
+            span.set_attribute(KeyValue::new("geoip.country_code", country_code.clone()));
+        }
+    }
+    result
+}
+
+/// Middleware that records a span for each request, continuing the trace from
+/// an incoming `traceparent` header if there is one.
+pub struct RequestTracing;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestTracing
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestTracingMiddleware<S>;
+    type Future = future::Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(RequestTracingMiddleware { service })
+    }
+}
+
+pub struct RequestTracingMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestTracingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let tracer = match req
+            .app_data::<Data<EndpointState>>()
+            .and_then(|state| state.tracer.clone())
+        {
+            Some(tracer) => tracer,
+            None => return Box::pin(self.service.call(req)),
+        };
+
+        let parent = TraceContextPropagator::new().extract(&HeaderExtractor(req.headers()));
+        // Spans are named after the route rather than the path, so that
+        // requests with different parameters or to unknown paths don't each
+        // get a name of their own.
+        let route = req.match_pattern();
+        // The query string is left out, since it may contain an API key.
+        let mut attributes = vec![
+            KeyValue::new("http.request.method", req.method().as_str().to_owned()),
+            KeyValue::new("url.path", req.path().to_owned()),
+        ];
+        if let Some(route) = &route {
+            attributes.push(KeyValue::new("http.route", route.clone()));
+        }
+        let span = tracer
+            .span_builder(format!(
+                "{} {}",
+                req.method(),
+                route.as_deref().unwrap_or("unmatched")
+            ))
+            .with_kind(SpanKind::Server)
+            .with_attributes(attributes)
+            .start_with_context(&tracer, &parent);
+        let context = parent.with_span(span);
+        req.extensions_mut().insert(context.clone());
+
+        Box::pin(self.service.call(req).then(move |res| {
+            let span = context.span();
+            match &res {
+                Ok(response) => {
+                    let status = response.status();
+                    span.set_attribute(KeyValue::new(
+                        "http.response.status_code",
+                        i64::from(status.as_u16()),
+                    ));
+                    if status.is_server_error() {
+                        span.set_status(Status::error(status.to_string()));
+                    }
+                }
+                Err(err) => span.set_status(Status::error(err.to_string())),
+            }
+            span.end();
+            future::ready(res)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RequestTracing;
+    use crate::{endpoints::EndpointState, geoip::GeoIp};
+    use actix_web::{
+        test::{self, TestRequest},
+        web::{self, Data},
+        App,
+    };
+    use opentelemetry::{
+        trace::{SpanKind, Status, TracerProvider},
+        Value,
+    };
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider, SpanData};
+    use std::sync::Arc;
+
+    /// A provider that exports every span to `exporter` as soon as it ends.
+    fn provider(exporter: &InMemorySpanExporter) -> SdkTracerProvider {
+        SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build()
+    }
+
+    fn attribute(span: &SpanData, key: &str) -> Option<Value> {
+        span.attributes
+            .iter()
+            .find(|attribute| attribute.key.as_str() == key)
+            .map(|attribute| attribute.value.clone())
+    }
+
+    #[actix_rt::test]
+    async fn test_request_spans() -> Result<(), Box<dyn std::error::Error>> {
+        let exporter = InMemorySpanExporter::default();
+        let provider = provider(&exporter);
+        let state = EndpointState {
+            geoip: Arc::new(GeoIp::builder().path("./GeoLite2-Country.mmdb").build()?),
+            tracer: Some(provider.tracer("test")),
+            trusted_proxies: vec!["192.0.2.1/32".parse()?],
+            ..EndpointState::default()
+        };
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .wrap(RequestTracing)
+                .route(
+                    "/api/v1/classify_client/",
+                    web::get().to(crate::endpoints::classify::classify_client),
+                ),
+        )
+        .await;
+
+        let request = TestRequest::get()
+            .uri("/api/v1/classify_client/")
+            .peer_addr("192.0.2.1:8000".parse()?)
+            .insert_header(("x-forwarded-for", "7.7.7.7"))
+            .insert_header((
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            ))
+            .to_request();
+        let response = test::call_service(&service, request).await;
+        assert_eq!(response.status(), 200);
+
+        let spans = exporter.get_finished_spans()?;
+        let names: Vec<&str> = spans.iter().map(|span| span.name.as_ref()).collect();
+        assert_eq!(
+            names,
+            vec!["client_ip", "GeoIp::locate", "GET /api/v1/classify_client/"]
+        );
+
+        let (client_ip, locate, request) = (&spans[0], &spans[1], &spans[2]);
+        assert_eq!(
+            request.span_context.trace_id().to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736",
+            "the incoming trace should be continued"
+        );
+        assert_eq!(request.parent_span_id.to_string(), "00f067aa0ba902b7");
+        assert_eq!(request.span_kind, SpanKind::Server);
+        assert_eq!(request.status, Status::Unset);
+        assert_eq!(
+            attribute(request, "http.response.status_code"),
+            Some(Value::I64(200))
+        );
+        assert_eq!(
+            attribute(request, "http.route"),
+            Some(Value::from("/api/v1/classify_client/"))
+        );
+        for child in [client_ip, locate] {
+            assert_eq!(
+                child.span_context.trace_id(),
+                request.span_context.trace_id()
+            );
+            assert_eq!(child.parent_span_id, request.span_context.span_id());
+        }
+        assert_eq!(
+            attribute(client_ip, "client_ip.trust_chain_position"),
+            Some(Value::I64(1))
+        );
+        assert_eq!(
+            attribute(client_ip, "client_ip.trust_chain_length"),
+            Some(Value::I64(2))
+        );
+        assert_eq!(
+            attribute(locate, "geoip.outcome"),
+            Some(Value::from("found"))
+        );
+        assert_eq!(
+            attribute(locate, "geoip.country_code"),
+            Some(Value::from("US"))
+        );
+
+        // Requests that don't match a route share one span name
+        let request = TestRequest::get().uri("/nothing/here").to_request();
+        let response = test::call_service(&service, request).await;
+        assert_eq!(response.status(), 404);
+        let spans = exporter.get_finished_spans()?;
+        assert_eq!(spans.last().unwrap().name, "GET unmatched");
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_unsampled_traces_are_not_exported() -> Result<(), Box<dyn std::error::Error>> {
+        let exporter = InMemorySpanExporter::default();
+        let provider = provider(&exporter);
+        let state = EndpointState {
+            tracer: Some(provider.tracer("test")),
+            ..EndpointState::default()
+        };
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .wrap(RequestTracing)
+                .route("/", web::get().to(actix_web::HttpResponse::Ok)),
+        )
+        .await;
+
+        let request = TestRequest::get()
+            .uri("/")
+            .insert_header((
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00",
+            ))
+            .to_request();
+        test::call_service(&service, request).await;
+        assert!(exporter.get_finished_spans()?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,298 @@
+//! TLS termination for the main server, so that it can be run without a proxy
+//! in front of it.
+
+use crate::{errors::ClassifyError, reload::Reloadable};
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::CertificateDer,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+/// A certificate and private key loaded from PEM files. The files are watched
+/// for changes, so that renewed certificates are used for new connections
+/// without a restart.
+#[derive(Debug)]
+pub struct TlsCertificate {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    provider: Arc<CryptoProvider>,
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl TlsCertificate {
+    /// Load the certificate chain at `cert_path` and the private key at
+    /// `key_path`. The key may be in PKCS#8, PKCS#1 or SEC1 format.
+    pub fn open<P: Into<PathBuf>>(cert_path: P, key_path: P) -> Result<Self, ClassifyError> {
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+        let provider = Arc::new(ring::default_provider());
+        let key = load(&cert_path, &key_path, &provider)?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            provider,
+            key: RwLock::new(Arc::new(key)),
+        })
+    }
+
+    /// A server configuration that presents the current certificate to each
+    /// new connection. It offers HTTP/2 as well as HTTP/1.1 through ALPN,
+    /// once `HttpServer` has added the protocols it serves.
+    pub fn server_config(self: &Arc<Self>) -> Result<ServerConfig, ClassifyError> {
+        let config = ServerConfig::builder_with_provider(Arc::clone(&self.provider))
+            .with_safe_default_protocol_versions()
+            .map_err(|err| ClassifyError::from_source("TLS", err))?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::clone(self) as Arc<dyn ResolvesServerCert>);
+        Ok(config)
+    }
+
+    fn current(&self) -> Arc<CertifiedKey> {
+        Arc::clone(&self.key.read().expect("tls certificate lock poisoned"))
+    }
+}
+
+impl ResolvesServerCert for TlsCertificate {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+impl Reloadable for TlsCertificate {
+    fn name(&self) -> &'static str {
+        "tls"
+    }
+
+    fn watched_paths(&self) -> Vec<PathBuf> {
+        vec![self.cert_path.clone(), self.key_path.clone()]
+    }
+
+    /// Read the certificate and key again. Connections that are already open
+    /// keep using the certificate they were accepted with.
+    fn reload(&self) -> Result<(), ClassifyError> {
+        let key = load(&self.cert_path, &self.key_path, &self.provider)?;
+        *self.key.write().expect("tls certificate lock poisoned") = Arc::new(key);
+        Ok(())
+    }
+}
+
+fn load(
+    cert_path: &Path,
+    key_path: &Path,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey, ClassifyError> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|err| ClassifyError::from_source(path.display(), err))
+    };
+
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+        .collect::<Result<Vec<CertificateDer>, _>>()
+        .map_err(|err| ClassifyError::from_source(cert_path.display(), err))?;
+    if certs.is_empty() {
+        return Err(ClassifyError::from_source(
+            cert_path.display(),
+            "no certificates found",
+        ));
+    }
+    let key = rustls_pemfile::private_key(&mut open(key_path)?)
+        .map_err(|err| ClassifyError::from_source(key_path.display(), err))?
+        .ok_or_else(|| ClassifyError::from_source(key_path.display(), "no private key found"))?;
+
+    // Also checks that the key belongs to the certificate.
+    CertifiedKey::from_der(certs, key, provider)
+        .map_err(|err| ClassifyError::from_source(key_path.display(), err))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::TlsCertificate;
+    use crate::reload::{tests::TempDir, Reloadable};
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use rustls::{
+        pki_types::{CertificateDer, ServerName},
+        ClientConfig, ClientConnection, RootCertStore, StreamOwned,
+    };
+    use std::{
+        fs,
+        io::{Read, Write},
+        net::{SocketAddr, TcpListener, TcpStream},
+        path::{Path, PathBuf},
+        sync::Arc,
+        time::Duration,
+    };
+
+    /// Write a new self-signed certificate for "localhost", and its key, to
+    /// `dir`. Returns the paths and the certificate.
+    pub fn self_signed_cert(dir: &Path) -> (PathBuf, PathBuf, CertificateDer<'static>) {
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+
+        fs::create_dir_all(dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        fs::write(&cert_path, cert.pem()).unwrap();
+        fs::write(&key_path, key_pair.serialize_pem()).unwrap();
+        (cert_path, key_path, cert.der().clone())
+    }
+
+    /// Connect over TLS, trusting only `cert` and offering the `alpn`
+    /// protocols. Returns the stream once the handshake is done.
+    fn connect(
+        addr: SocketAddr,
+        cert: &CertificateDer<'static>,
+        alpn: &[&[u8]],
+    ) -> StreamOwned<ClientConnection, TcpStream> {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.clone()).unwrap();
+        let mut config =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let conn = ClientConnection::new(Arc::new(config), server_name).unwrap();
+        let mut stream = StreamOwned::new(conn, TcpStream::connect(addr).unwrap());
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock).unwrap();
+        }
+        stream
+    }
+
+    /// Make an HTTP/1.1 request over TLS, trusting only `cert`, and return the
+    /// response and the certificate that the server presented.
+    fn https_get(
+        addr: SocketAddr,
+        cert: &CertificateDer<'static>,
+        path: &str,
+    ) -> (String, CertificateDer<'static>) {
+        let mut stream = connect(addr, cert, &[b"http/1.1"]);
+        let peer_cert = stream.conn.peer_certificates().unwrap()[0].clone();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        (response, peer_cert)
+    }
+
+    #[actix_rt::test]
+    async fn test_tls_server() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TempDir::new();
+        let (cert_path, key_path, cert) = self_signed_cert(dir.path());
+        let certificate = Arc::new(TlsCertificate::open(&cert_path, &key_path)?);
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = HttpServer::new(|| {
+            App::new().route(
+                "/",
+                web::get().to(|req: HttpRequest| async move {
+                    let config = req.app_config();
+                    HttpResponse::Ok().body(format!(
+                        "secure={} host={} local={} peer={}",
+                        config.secure(),
+                        config.host(),
+                        config.local_addr(),
+                        req.peer_addr().unwrap().ip()
+                    ))
+                }),
+            )
+        })
+        .disable_signals()
+        .workers(1)
+        .tls_handshake_timeout(Duration::from_millis(500))
+        .listen_rustls_0_23(listener, certificate.server_config()?)?
+        .run();
+        let handle = server.handle();
+        actix_rt::spawn(server);
+
+        let (response, peer_cert) = {
+            let cert = cert.clone();
+            actix_rt::task::spawn_blocking(move || https_get(addr, &cert, "/")).await?
+        };
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(
+            response.ends_with(&format!(
+                "secure=true host={} local={} peer=127.0.0.1",
+                addr, addr
+            )),
+            "{}",
+            response
+        );
+        assert_eq!(peer_cert, cert);
+
+        // HTTP/2 is served to clients that ask for it
+        let settings = {
+            let cert = cert.clone();
+            actix_rt::task::spawn_blocking(move || {
+                let mut stream = connect(addr, &cert, &[b"h2", b"http/1.1"]);
+                assert_eq!(stream.conn.alpn_protocol(), Some(&b"h2"[..]));
+                stream
+                    .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n")
+                    .unwrap();
+                let mut frame_header = [0; 9];
+                stream.read_exact(&mut frame_header).unwrap();
+                frame_header
+            })
+            .await?
+        };
+        assert_eq!(settings[3], 0x4, "expected a SETTINGS frame");
+
+        // A new certificate is used for new connections once reloaded
+        let (_, _, new_cert) = self_signed_cert(dir.path());
+        certificate.reload()?;
+        let (response, peer_cert) = {
+            let new_cert = new_cert.clone();
+            actix_rt::task::spawn_blocking(move || https_get(addr, &new_cert, "/")).await?
+        };
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert_eq!(peer_cert, new_cert);
+
+        // Clients that never start the handshake are disconnected
+        let closed = actix_rt::task::spawn_blocking(move || {
+            let mut stream = TcpStream::connect(addr)?;
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+            stream.read(&mut [0; 1])
+        })
+        .await?;
+        assert_eq!(closed?, 0);
+
+        handle.stop(false).await;
+        Ok(())
+    }
+
+    #[test]
+    fn test_tls_certificate_keeps_previous_on_failure() {
+        let dir = TempDir::new();
+        let (cert_path, key_path, _) = self_signed_cert(dir.path());
+        let certificate = TlsCertificate::open(&cert_path, &key_path).unwrap();
+        let before = certificate.current();
+
+        fs::write(&key_path, "not a key").unwrap();
+        assert!(certificate.reload().is_err());
+        assert!(Arc::ptr_eq(&before, &certificate.current()));
+
+        assert!(TlsCertificate::open(&cert_path, &key_path).is_err());
+
+        // A key that doesn't belong to the certificate is rejected too
+        let other = dir.path().join("other");
+        let (_, other_key_path, _) = self_signed_cert(&other);
+        assert!(TlsCertificate::open(&cert_path, &other_key_path).is_err());
+    }
+}
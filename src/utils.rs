@@ -1,6 +1,18 @@
-use crate::{endpoints::EndpointState, errors::ClassifyError};
-use actix_web::{web::Data, HttpRequest};
-use std::net::IpAddr;
+use crate::{
+    endpoints::EndpointState,
+    errors::ClassifyError,
+    settings::{ForwardedHeader, InvalidHopPolicy},
+    telemetry::RequestSpans,
+};
+use actix_web::{web::Data, HttpMessage, HttpRequest};
+use opentelemetry::{
+    trace::{Span, Status},
+    KeyValue,
+};
+use std::{
+    borrow::Cow,
+    net::{IpAddr, SocketAddr},
+};
 
 pub trait RequestClientIp<S> {
     /// Determine the IP address of the client making a request, based on network
@@ -8,59 +20,250 @@ pub trait RequestClientIp<S> {
     ///
     /// Actix has a method to do this, but it returns a string, and doesn't strip
     /// off ports if present, so it is difficult to use.
+    ///
+    /// The result is kept with the request, so that middleware and endpoints
+    /// can all ask for it without reporting invalid hops more than once.
     fn client_ip(&self) -> Result<IpAddr, ClassifyError>;
 }
 
+/// The result of `client_ip`, stored in the request's extensions.
+#[derive(Clone)]
+struct ResolvedClientIp(Result<IpAddr, ClassifyError>);
+
 pub trait RequestTraceIps<'a> {
     /// Iterate all known proxy and client IPs, starting with the IPs closest to
     /// the server, and ending with the alleged client.
-    fn trace_ips(&'a self) -> Vec<IpAddr>;
+    ///
+    /// Fails if a hop is invalid and the policy is `InvalidHopPolicy::Fail`.
+    fn trace_ips(&'a self) -> Result<Vec<IpAddr>, ClassifyError>;
 }
 
 impl RequestClientIp<EndpointState> for HttpRequest {
     fn client_ip(&self) -> Result<IpAddr, ClassifyError> {
-        let trusted_proxy_list = &self
-            .app_data::<Data<EndpointState>>()
-            .expect("Expected app state")
-            .trusted_proxies;
+        if let Some(ResolvedClientIp(resolved)) = self.extensions().get() {
+            return resolved.clone();
+        }
+        let resolved = resolve_client_ip(self);
+        self.extensions_mut()
+            .insert(ResolvedClientIp(resolved.clone()));
+        resolved
+    }
+}
+
+fn resolve_client_ip(req: &HttpRequest) -> Result<IpAddr, ClassifyError> {
+    let state = req
+        .app_data::<Data<EndpointState>>()
+        .expect("Expected app state");
+
+    let mut span = req.child_span("client_ip");
+
+    if let Some(ip) = trusted_header_ip(req, state) {
+        if let Some(span) = &mut span {
+            span.set_attribute(KeyValue::new("client_ip.source", "trusted_header"));
+        }
+        return Ok(ip);
+    }
 
-        let is_trusted_ip =
-            |ip: &&IpAddr| trusted_proxy_list.iter().any(|range| range.contains(*ip));
+    let trace = req.trace_ips()?;
+    let position = match state.trusted_proxy_hops {
+        Some(hops) if hops < trace.len() => Ok(hops),
+        Some(hops) => Err(ClassifyError::new(format!(
+            "Expected {} trusted proxy hops, which needs at least {} addresses, \
+             but the request only has {}",
+            hops,
+            hops + 1,
+            trace.len()
+        ))),
+        None => {
+            let trusted_proxy_list = &state.trusted_proxies;
+            trace
+                .iter()
+                .position(|ip| !trusted_proxy_list.iter().any(|range| range.contains(ip)))
+                .ok_or_else(|| ClassifyError::new("Could not determine IP"))
+        }
+    };
+    if let Some(span) = &mut span {
+        span.set_attribute(KeyValue::new("client_ip.source", "trace"));
+        span.set_attribute(KeyValue::new(
+            "client_ip.trust_chain_length",
+            trace.len() as i64,
+        ));
+        match &position {
+            // The position counts hops from the server, starting at 0 for the peer.
+            Ok(position) => span.set_attribute(KeyValue::new(
+                "client_ip.trust_chain_position",
+                *position as i64,
+            )),
+            Err(err) => span.set_status(Status::error(err.to_string())),
+        }
+    }
 
-        self.trace_ips()
-            .iter()
-            .find(|ip| !is_trusted_ip(ip))
-            .ok_or_else(|| ClassifyError::new("Could not determine IP"))
-            .copied()
+    position.map(|position| trace[position])
+}
+
+/// The address in `trusted_client_ip_header`, if the request comes directly
+/// from one of `trusted_client_ip_header_peers`. Anyone else could set the
+/// header to anything.
+fn trusted_header_ip(req: &HttpRequest, state: &EndpointState) -> Option<IpAddr> {
+    let header = state.trusted_client_ip_header.as_ref()?;
+    let peer = req.peer_addr()?.ip();
+    if !state
+        .trusted_client_ip_header_peers
+        .iter()
+        .any(|range| range.contains(&peer))
+    {
+        return None;
     }
+    let value = req.headers().get(header)?.to_str().ok()?;
+    parse_node(value.trim())
 }
 
 impl<'a> RequestTraceIps<'a> for HttpRequest {
-    fn trace_ips(&'a self) -> Vec<IpAddr> {
+    fn trace_ips(&'a self) -> Result<Vec<IpAddr>, ClassifyError> {
         let mut trace: Vec<IpAddr> = Vec::new();
 
         if let Some(peer_addr) = self.peer_addr() {
             trace.push(peer_addr.ip());
         }
 
-        if let Some(x_forwarded_for) = self.headers().get("X-Forwarded-For") {
-            if let Ok(header) = x_forwarded_for.to_str() {
-                let mut header_ips: Vec<IpAddr> =
-                    header.split(',').flat_map(|ip| ip.trim().parse()).collect();
-                header_ips.reverse();
-                trace.append(&mut header_ips);
+        let state = self.app_data::<Data<EndpointState>>();
+        let forwarded_header = state
+            .map(|state| state.forwarded_header)
+            .unwrap_or_default();
+
+        match forwarded_header {
+            ForwardedHeader::XForwardedFor => {
+                let policy = state
+                    .map(|state| state.invalid_hop_policy)
+                    .unwrap_or_default();
+                // Multiple headers are equivalent to one with their values
+                // joined by commas.
+                let headers: Vec<Cow<str>> = self
+                    .headers()
+                    .get_all("X-Forwarded-For")
+                    .map(|header| String::from_utf8_lossy(header.as_bytes()))
+                    .collect();
+                // Empty entries, like from a trailing comma, carry no hop at
+                // all, so they are left out rather than treated as invalid.
+                let entries: Vec<&str> = headers
+                    .iter()
+                    .flat_map(|header| header.split(','))
+                    .map(str::trim)
+                    .filter(|entry| !entry.is_empty())
+                    .collect();
+
+                for entry in entries.into_iter().rev() {
+                    if let Some(ip) = parse_node(entry) {
+                        trace.push(ip);
+                        continue;
+                    }
+
+                    if let Some(state) = state {
+                        slog::debug!(state.log, "Invalid X-Forwarded-For entry {:?}", entry);
+                        state
+                            .metrics
+                            .incr_with_tags("invalid_forwarded_hop")
+                            .with_tag("policy", policy.as_str())
+                            .send();
+                    }
+                    match policy {
+                        InvalidHopPolicy::Skip => continue,
+                        InvalidHopPolicy::Stop => break,
+                        InvalidHopPolicy::Fail => {
+                            return Err(ClassifyError::new(format!(
+                                "Invalid X-Forwarded-For entry {:?}",
+                                entry
+                            )))
+                        }
+                    }
+                }
+            }
+            ForwardedHeader::Forwarded => {
+                let mut elements: Vec<Option<IpAddr>> = self
+                    .headers()
+                    .get_all("Forwarded")
+                    .flat_map(|header| header.to_str())
+                    .flat_map(forwarded_for)
+                    .collect();
+                elements.reverse();
+                // A hop that hides its address could have been preceded by
+                // anything, so nothing before it can be trusted.
+                trace.extend(elements.into_iter().map_while(|ip| ip));
             }
         }
 
-        trace
+        Ok(trace)
     }
 }
 
+/// The address in the `for` parameter of each element of a `Forwarded`
+/// header (RFC 7239), in order. Elements without a `for` parameter, or with
+/// an obfuscated identifier or "unknown" instead of an address, give `None`.
+fn forwarded_for(header: &str) -> impl Iterator<Item = Option<IpAddr>> + '_ {
+    split_unquoted(header, ',').into_iter().map(|element| {
+        split_unquoted(element, ';').into_iter().find_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            if !name.trim().eq_ignore_ascii_case("for") {
+                return None;
+            }
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+            parse_node(value)
+        })
+    })
+}
+
+/// Split `value` on `separator`, except inside quoted strings.
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (index, c) in value.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            parts.push(&value[start..index]);
+            start = index + c.len_utf8();
+        }
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
+/// Parse an address that may have a port, like "192.0.2.1:8080" or
+/// "[2001:db8::1]:8080". IPv6 addresses without a port may leave out the
+/// brackets.
+fn parse_node(value: &str) -> Option<IpAddr> {
+    value
+        .parse()
+        .or_else(|_| value.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| {
+            value
+                .strip_prefix('[')
+                .and_then(|value| value.strip_suffix(']'))
+                .ok_or(())
+                .and_then(|value| value.parse().map_err(|_| ()))
+        })
+        .ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::metrics::{tests::TestMetricSink, Metrics};
     use actix_web::test::TestRequest;
-    use std::net::{IpAddr, Ipv4Addr};
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        sync::{Arc, Mutex},
+    };
 
     #[test]
     fn trace_ip_works() {
@@ -69,20 +272,176 @@ mod tests {
             .to_http_request();
         assert_eq!(
             req.trace_ips(),
-            vec![
+            Ok(vec![
                 IpAddr::V4(Ipv4Addr::new(9, 10, 11, 12)),
                 IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8)),
                 IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)),
-            ],
+            ]),
             "IPs in x-forwarded-for should be iterated in reverse order",
         );
     }
 
-    // Note that in all of the below tests, there aren't any networks involved,
+    #[test]
+    fn trace_ip_ports_and_multiple_headers() {
+        let req = TestRequest::get()
+            .insert_header(("x-forwarded-for", "1.2.3.4:1234, [2001:db8::1]:443"))
+            .append_header(("x-forwarded-for", "2001:db8::2, 5.6.7.8"))
+            .to_http_request();
+        assert_eq!(
+            req.trace_ips(),
+            Ok(vec![
+                IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8)),
+                IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2)),
+                IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+                IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)),
+            ]),
+            "Ports should be removed, and later headers come after earlier ones",
+        );
+    }
+
+    #[test]
+    fn trace_ip_invalid_hop_policies() {
+        let expected = [
+            (
+                InvalidHopPolicy::Skip,
+                Ok(vec![
+                    IpAddr::V4(Ipv4Addr::new(9, 10, 11, 12)),
+                    IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)),
+                ]),
+            ),
+            (
+                InvalidHopPolicy::Stop,
+                Ok(vec![IpAddr::V4(Ipv4Addr::new(9, 10, 11, 12))]),
+            ),
+            (
+                InvalidHopPolicy::Fail,
+                Err(ClassifyError::new(
+                    "Invalid X-Forwarded-For entry \"garbage\"",
+                )),
+            ),
+        ];
+        for (policy, expected) in expected {
+            let log = Arc::new(Mutex::new(Vec::new()));
+            let state = EndpointState {
+                invalid_hop_policy: policy,
+                metrics: Arc::new(Metrics::from_sink(
+                    "test",
+                    TestMetricSink { log: log.clone() },
+                )),
+                ..EndpointState::default()
+            };
+            let req = TestRequest::get()
+                .insert_header(("x-forwarded-for", "1.2.3.4, garbage, 9.10.11.12,"))
+                .app_data(Data::new(state))
+                .to_http_request();
+
+            assert_eq!(req.trace_ips(), expected, "with policy {:?}", policy);
+            assert_eq!(
+                *log.lock().unwrap(),
+                vec![format!(
+                    "test.invalid_forwarded_hop:1|c|#policy:{}",
+                    policy.as_str()
+                )]
+            );
+        }
+    }
+
+    fn forwarded_state() -> Data<EndpointState> {
+        Data::new(EndpointState {
+            forwarded_header: ForwardedHeader::Forwarded,
+            ..EndpointState::default()
+        })
+    }
+
+    #[test]
+    fn forwarded_trace_ip_works() {
+        let req = TestRequest::get()
+            .insert_header((
+                "forwarded",
+                "for=1.2.3.4, for=5.6.7.8;proto=https, For=9.10.11.12",
+            ))
+            .app_data(forwarded_state())
+            .to_http_request();
+        assert_eq!(
+            req.trace_ips(),
+            Ok(vec![
+                IpAddr::V4(Ipv4Addr::new(9, 10, 11, 12)),
+                IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8)),
+                IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)),
+            ]),
+            "IPs in forwarded should be iterated in reverse order",
+        );
+    }
+
+    #[test]
+    fn forwarded_trace_ip_ports_and_ipv6() {
+        let req = TestRequest::get()
+            .insert_header((
+                "forwarded",
+                r#"for="[2001:db8:cafe::17]:4711", for="[2001:db8::1]", for="1.2.3.4:8080""#,
+            ))
+            .append_header(("forwarded", "by=5.6.7.8;for=\"2001:db8::2\""))
+            .app_data(forwarded_state())
+            .to_http_request();
+        assert_eq!(
+            req.trace_ips(),
+            Ok(vec![
+                IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2)),
+                IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)),
+                IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+                IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0xcafe, 0, 0, 0, 0, 0x17)),
+            ]),
+            "Ports and brackets should be removed, across multiple headers",
+        );
+    }
+
+    #[test]
+    fn forwarded_trace_ip_stops_at_obfuscated() {
+        for hidden in [
+            "for=_hidden",
+            "for=unknown",
+            r#"for="_a:_b""#,
+            "proto=https",
+        ] {
+            let req = TestRequest::get()
+                .insert_header(("forwarded", format!("for=1.2.3.4, {}, for=5.6.7.8", hidden)))
+                .app_data(forwarded_state())
+                .to_http_request();
+            assert_eq!(
+                req.trace_ips(),
+                Ok(vec![IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8))]),
+                "IPs before {} should not be used",
+                hidden
+            );
+        }
+    }
+
+    #[test]
+    fn trace_ip_uses_only_chosen_header() {
+        let req = TestRequest::get()
+            .insert_header(("x-forwarded-for", "1.2.3.4"))
+            .insert_header(("forwarded", "for=5.6.7.8"))
+            .to_http_request();
+        assert_eq!(
+            req.trace_ips(),
+            Ok(vec![IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))])
+        );
+
+        let req = TestRequest::get()
+            .insert_header(("x-forwarded-for", "1.2.3.4"))
+            .insert_header(("forwarded", "for=5.6.7.8"))
+            .app_data(forwarded_state())
+            .to_http_request();
+        assert_eq!(
+            req.trace_ips(),
+            Ok(vec![IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8))])
+        );
+    }
+
+    // Note that in most of the below tests, there aren't any networks involved,
     // so the requests don't have a peer address. As such, the X-Forwarded-For
-    // header is the only thing considered to determine the client IP. Actix
-    // doesn't seem to provide a way to create a request with a mocked peer
-    // address.
+    // header is the only thing considered to determine the client IP, unless a
+    // peer address is mocked with `TestRequest::peer_addr`.
 
     #[test]
     fn get_client_ip_no_proxies() -> Result<(), Box<dyn std::error::Error + 'static>> {
@@ -130,6 +489,168 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn get_client_ip_forwarded_one_proxies() -> Result<(), Box<dyn std::error::Error + 'static>> {
+        let _sys = actix::System::new();
+        let state = EndpointState {
+            forwarded_header: ForwardedHeader::Forwarded,
+            trusted_proxies: vec!["2001:db8::/32".parse()?],
+            ..EndpointState::default()
+        };
+
+        let req = TestRequest::get()
+            .insert_header(("forwarded", r#"for=1.2.3.4:1234, for="[2001:db8::1]:443""#))
+            .app_data(Data::new(state))
+            .to_http_request();
+
+        assert_eq!(
+            req.client_ip()?,
+            IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)),
+            "With one proxy, the second-from-the-right ip should be used"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_client_ip_trusted_header() -> Result<(), Box<dyn std::error::Error + 'static>> {
+        let _sys = actix::System::new();
+        let state = Data::new(EndpointState {
+            trusted_client_ip_header: Some("CF-Connecting-IP".to_owned()),
+            trusted_client_ip_header_peers: vec!["5.6.0.0/16".parse()?],
+            trusted_proxies: vec!["5.6.0.0/16".parse()?],
+            ..EndpointState::default()
+        });
+
+        let req = TestRequest::get()
+            .peer_addr("5.6.7.8:443".parse()?)
+            .insert_header(("cf-connecting-ip", "1.2.3.4"))
+            .insert_header(("x-forwarded-for", "9.10.11.12"))
+            .app_data(state.clone())
+            .to_http_request();
+        assert_eq!(
+            req.client_ip()?,
+            IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)),
+            "The header should be used when the peer is trusted"
+        );
+
+        let req = TestRequest::get()
+            .peer_addr("13.14.15.16:443".parse()?)
+            .insert_header(("cf-connecting-ip", "1.2.3.4"))
+            .app_data(state.clone())
+            .to_http_request();
+        assert_eq!(
+            req.client_ip()?,
+            IpAddr::V4(Ipv4Addr::new(13, 14, 15, 16)),
+            "The header should be ignored when the peer isn't trusted"
+        );
+
+        for header in [None, Some("garbage")] {
+            let mut req = TestRequest::get()
+                .peer_addr("5.6.7.8:443".parse()?)
+                .insert_header(("x-forwarded-for", "9.10.11.12"))
+                .app_data(state.clone());
+            if let Some(header) = header {
+                req = req.insert_header(("cf-connecting-ip", header));
+            }
+            assert_eq!(
+                req.to_http_request().client_ip()?,
+                IpAddr::V4(Ipv4Addr::new(9, 10, 11, 12)),
+                "Without a valid header, the trace should be used"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_client_ip_proxy_hops() -> Result<(), Box<dyn std::error::Error + 'static>> {
+        let _sys = actix::System::new();
+        for (hops, expected) in [
+            (0, Ipv4Addr::new(5, 6, 7, 8)),
+            (1, Ipv4Addr::new(1, 2, 3, 4)),
+        ] {
+            let state = EndpointState {
+                trusted_proxy_hops: Some(hops),
+                // Ignored when counting hops
+                trusted_proxies: vec!["5.6.7.8/32".parse()?, "1.2.3.4/32".parse()?],
+                ..EndpointState::default()
+            };
+
+            let req = TestRequest::get()
+                .insert_header(("x-forwarded-for", "1.2.3.4, 5.6.7.8"))
+                .app_data(Data::new(state))
+                .to_http_request();
+
+            assert_eq!(
+                req.client_ip()?,
+                IpAddr::V4(expected),
+                "With {} hops, the ip {} from the right should be used",
+                hops,
+                hops
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_client_ip_too_few_proxy_hops() -> Result<(), Box<dyn std::error::Error + 'static>> {
+        let _sys = actix::System::new();
+        let state = EndpointState {
+            trusted_proxy_hops: Some(2),
+            ..EndpointState::default()
+        };
+
+        let req = TestRequest::get()
+            .insert_header(("x-forwarded-for", "1.2.3.4, 5.6.7.8"))
+            .app_data(Data::new(state))
+            .to_http_request();
+
+        assert_eq!(
+            req.client_ip(),
+            Err(ClassifyError::new(
+                "Expected 2 trusted proxy hops, which needs at least 3 addresses, but the request only has 2"
+            )),
+            "With a chain shorter than the hops, no ip is given"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_client_ip_reports_invalid_hops_once() -> Result<(), Box<dyn std::error::Error + 'static>>
+    {
+        let _sys = actix::System::new();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let state = EndpointState {
+            invalid_hop_policy: InvalidHopPolicy::Fail,
+            metrics: Arc::new(Metrics::from_sink(
+                "test",
+                TestMetricSink { log: log.clone() },
+            )),
+            ..EndpointState::default()
+        };
+
+        let req = TestRequest::get()
+            .insert_header(("x-forwarded-for", "garbage"))
+            .app_data(Data::new(state))
+            .to_http_request();
+
+        // Middleware and endpoints share the result for the request
+        let expected = Err(ClassifyError::new(
+            "Invalid X-Forwarded-For entry \"garbage\"",
+        ));
+        assert_eq!(req.client_ip(), expected);
+        assert_eq!(req.client_ip(), expected);
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["test.invalid_forwarded_hop:1|c|#policy:fail"]
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn get_client_ip_too_many_proxies() -> Result<(), Box<dyn std::error::Error + 'static>> {
         let _sys = actix::System::new();